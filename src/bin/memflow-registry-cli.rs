@@ -0,0 +1,376 @@
+//! Command line interface to manage plugins in a memflow registry
+
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use memflow::plugins::plugin_analyzer::PluginFileType;
+
+use memflow_registry::{
+    client::RegistryClient,
+    pki::{self, SignatureScheme},
+    rest::models::PluginUploadResponse,
+    storage::{database::PluginDatabaseFindParams, TagEntry},
+    Error, PluginUri, PluginVariant, Result, SignatureGenerator, SignatureVerifier,
+    MEMFLOW_DEFAULT_REGISTRY, MEMFLOW_DEFAULT_REGISTRY_VERIFYING_KEY,
+};
+
+#[derive(Parser)]
+#[command(version, about = "Manages plugins in a memflow registry")]
+struct Cli {
+    /// Url of the registry
+    #[arg(long, env = "MEMFLOW_REGISTRY", global = true)]
+    registry: Option<String>,
+
+    /// Api token used to push, remove and tag files
+    #[arg(
+        long,
+        env = "MEMFLOW_REGISTRY_TOKEN",
+        hide_env_values = true,
+        global = true
+    )]
+    token: Option<String>,
+
+    /// Public key files are verified with, defaults to the key of the official registry
+    #[arg(long, env = "MEMFLOW_REGISTRY_PUBLIC_KEY_FILE", global = true)]
+    public_key: Option<PathBuf>,
+
+    /// Public key of the registry, index responses are only trusted if they are signed by this key
+    #[arg(long, env = "MEMFLOW_REGISTRY_INDEX_KEY_FILE", global = true)]
+    index_key: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Generates a new key pair
+    Keygen {
+        /// Path of the private key, defaults to `ec-secp256k1-priv-key.pem` or `ed25519-priv-key.pem`
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Type of the key
+        #[arg(long, value_enum, default_value_t = KeyType::Secp256k1)]
+        key_type: KeyType,
+        /// Path of the public key, defaults to the path of the private key with a `.pub.pem` extension
+        #[arg(long)]
+        public_output: Option<PathBuf>,
+        /// Encrypt the private key with a passphrase read from `MEMFLOW_PRIVATE_KEY_PASSPHRASE` or the terminal
+        #[arg(long)]
+        encrypt: bool,
+    },
+    /// Signs a plugin file and prints the signature
+    Sign {
+        file: PathBuf,
+        /// Private key the file is signed with
+        #[arg(short, long, env = "MEMFLOW_PRIVATE_KEY_FILE")]
+        key: PathBuf,
+    },
+    /// Verifies the signature of a plugin file against the public key
+    Verify {
+        file: PathBuf,
+        /// Hex encoded signature of the file
+        #[arg(short, long)]
+        signature: String,
+    },
+    /// Signs a plugin file and uploads it to the registry
+    Push {
+        file: PathBuf,
+        /// Private key the file is signed with
+        #[arg(short, long, env = "MEMFLOW_PRIVATE_KEY_FILE")]
+        key: Option<PathBuf>,
+        /// Existing signature of the file, the file is not signed again if set
+        #[arg(short, long)]
+        signature: Option<String>,
+    },
+    /// Downloads and verifies the latest file matching the plugin uri, e.g. `coredump:0.2`
+    Pull {
+        plugin_uri: String,
+        /// Output path, defaults to the library name of the plugin in the current directory
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Do not restrict the search to the current os and architecture
+        #[arg(long)]
+        all_archs: bool,
+    },
+    /// Lists all plugins or the variants of a single plugin
+    Ls {
+        plugin: Option<String>,
+        /// Plugin version, semantic version requirement, tag or digest prefix
+        #[arg(short, long)]
+        version: Option<String>,
+        /// Maximum number of variants
+        #[arg(short, long, default_value_t = 50)]
+        limit: usize,
+    },
+    /// Prints the metadata of a file
+    Inspect { digest: String },
+    /// Removes a file from the registry
+    Rm { digest: String },
+    /// Lists the tags of a plugin or moves a tag to the file with the given digest
+    Tag {
+        plugin: String,
+        #[arg(requires = "digest")]
+        tag: Option<String>,
+        digest: Option<String>,
+    },
+}
+
+/// Supported key types, see [`SignatureScheme`]
+#[derive(Clone, Copy, ValueEnum)]
+enum KeyType {
+    Secp256k1,
+    Ed25519,
+}
+
+impl KeyType {
+    fn scheme(self) -> SignatureScheme {
+        match self {
+            KeyType::Secp256k1 => SignatureScheme::EcdsaSecp256k1,
+            KeyType::Ed25519 => SignatureScheme::Ed25519,
+        }
+    }
+
+    fn default_output(self) -> &'static str {
+        match self {
+            KeyType::Secp256k1 => "ec-secp256k1-priv-key.pem",
+            KeyType::Ed25519 => "ed25519-priv-key.pem",
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("warn"));
+
+    let cli = Cli::parse();
+    if let Err(err) = run(cli).await {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    match &cli.command {
+        Command::Keygen {
+            output,
+            key_type,
+            public_output,
+            encrypt,
+        } => {
+            let output = output
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(key_type.default_output()));
+            let public_output = public_output
+                .clone()
+                .unwrap_or_else(|| output.with_extension("pub.pem"));
+            if output.exists() || public_output.exists() {
+                return Err(Error::AlreadyExists(format!(
+                    "refusing to overwrite {:?} or {:?}",
+                    output, public_output
+                )));
+            }
+
+            let passphrase = if *encrypt {
+                Some(new_passphrase()?)
+            } else {
+                None
+            };
+
+            let generator = SignatureGenerator::generate_with_scheme(key_type.scheme());
+            generator.write_pem(&output, passphrase.as_deref())?;
+            std::fs::write(&public_output, generator.public_key_pem()?)?;
+            println!("private key: {}", output.display());
+            println!("public key:  {}", public_output.display());
+            println!("fingerprint: {}", generator.fingerprint());
+        }
+        Command::Sign { file, key } => {
+            let mut generator = SignatureGenerator::new_interactive(key)?;
+            println!("{}", generator.sign(&std::fs::read(file)?)?);
+        }
+        Command::Verify { file, signature } => {
+            verifier(&cli)?.is_valid(&std::fs::read(file)?, signature)?;
+            println!("signature is valid");
+        }
+        Command::Push {
+            file,
+            key,
+            signature,
+        } => {
+            let client = client(&cli, cli.registry.as_deref())?;
+            let response = match (signature, key) {
+                (Some(signature), _) => client.upload_with_signature(file, signature).await?,
+                (None, Some(key)) => {
+                    client
+                        .upload(file, &mut SignatureGenerator::new_interactive(key)?)
+                        .await?
+                }
+                (None, None) => {
+                    return Err(Error::BadRequest(
+                        "either a private key or a signature is required".to_owned(),
+                    ))
+                }
+            };
+            match response {
+                PluginUploadResponse::Added => println!("added {}", file.display()),
+                PluginUploadResponse::AlreadyExists => {
+                    println!("{} already exists", file.display())
+                }
+            }
+        }
+        Command::Pull {
+            plugin_uri,
+            output,
+            all_archs,
+        } => {
+            let plugin_uri = PluginUri::with_defaults(
+                plugin_uri,
+                cli.registry.as_deref().unwrap_or(MEMFLOW_DEFAULT_REGISTRY),
+                "latest",
+            )?;
+            let client = client(&cli, Some(plugin_uri.registry()))?;
+            let variant = client.find_by_uri(&plugin_uri, *all_archs, None).await?;
+            let output = output
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(library_name(&variant)));
+            client.download_to_file(&variant, &output).await?;
+            println!(
+                "downloaded {} {} ({}) to {}",
+                variant.descriptor.name,
+                variant.descriptor.version,
+                variant.digest,
+                output.display()
+            );
+        }
+        Command::Ls { plugin: None, .. } => {
+            for plugin in client(&cli, cli.registry.as_deref())?.plugins().await? {
+                println!("{:<24} {}", plugin.name, plugin.description);
+            }
+        }
+        Command::Ls {
+            plugin: Some(plugin),
+            version,
+            limit,
+        } => {
+            let params = PluginDatabaseFindParams {
+                version: version.clone(),
+                limit: Some(*limit),
+                ..Default::default()
+            };
+            let variants = client(&cli, cli.registry.as_deref())?
+                .find_variants(plugin, &params)
+                .await?;
+            for variant in variants.iter() {
+                print_variant(variant);
+            }
+        }
+        Command::Inspect { digest } => {
+            let metadata = client(&cli, cli.registry.as_deref())?
+                .metadata(digest)
+                .await?;
+            println!("{}", serde_json::to_string_pretty(&metadata)?);
+        }
+        Command::Rm { digest } => {
+            client(&cli, cli.registry.as_deref())?
+                .delete(digest)
+                .await?;
+            println!("removed {}", digest);
+        }
+        Command::Tag {
+            plugin,
+            tag: Some(tag),
+            digest: Some(digest),
+        } => {
+            let entries = client(&cli, cli.registry.as_deref())?
+                .move_tag(plugin, tag, digest)
+                .await?;
+            print_tags(&entries);
+        }
+        Command::Tag { plugin, .. } => {
+            let entries = client(&cli, cli.registry.as_deref())?.tags(plugin).await?;
+            print_tags(&entries);
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates a client for the registry with the token and public key of the command line.
+fn client(cli: &Cli, registry: Option<&str>) -> Result<RegistryClient> {
+    let mut builder = RegistryClient::builder().with_verifier(verifier(cli)?);
+    if let Some(registry) = registry {
+        builder = builder.with_registry(registry);
+    }
+    if let Some(token) = &cli.token {
+        builder = builder.with_token(token);
+    }
+    if let Some(index_key) = &cli.index_key {
+        builder = builder.with_index_verifier(SignatureVerifier::new(index_key)?);
+    }
+    builder.build()
+}
+
+/// Returns the verifier for the public key of the command line or the key of the official registry.
+fn verifier(cli: &Cli) -> Result<SignatureVerifier> {
+    match &cli.public_key {
+        Some(public_key) => SignatureVerifier::new(public_key),
+        None => SignatureVerifier::with_str(MEMFLOW_DEFAULT_REGISTRY_VERIFYING_KEY),
+    }
+}
+
+/// Returns the passphrase from the environment or asks for it twice on the terminal.
+fn new_passphrase() -> Result<String> {
+    if let Ok(passphrase) = std::env::var(pki::PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+
+    let passphrase = pki::read_passphrase("passphrase: ")?;
+    if passphrase.is_empty() {
+        return Err(Error::BadRequest("passphrase must not be empty".to_owned()));
+    }
+    if pki::read_passphrase("repeat passphrase: ")? != passphrase {
+        return Err(Error::BadRequest("passphrases do not match".to_owned()));
+    }
+    Ok(passphrase)
+}
+
+/// Returns the file name memflow expects for the plugin.
+fn library_name(variant: &PluginVariant) -> String {
+    let name = &variant.descriptor.name;
+    match variant.descriptor.file_type {
+        PluginFileType::Pe => format!("memflow_{}.dll", name),
+        PluginFileType::Elf => format!("libmemflow_{}.so", name),
+        PluginFileType::Mach => format!("libmemflow_{}.dylib", name),
+    }
+}
+
+fn print_variant(variant: &PluginVariant) {
+    println!(
+        "{:<12} {:<64} {:<6} {:<8} abi={} {}{}",
+        variant.descriptor.version,
+        variant.digest,
+        format!("{:?}", variant.descriptor.file_type).to_lowercase(),
+        format!("{:?}", variant.descriptor.architecture).to_lowercase(),
+        variant.descriptor.plugin_version,
+        variant.created_at,
+        if variant.yanked_at.is_some() {
+            " (yanked)"
+        } else {
+            ""
+        }
+    );
+}
+
+fn print_tags(entries: &[TagEntry]) {
+    for entry in entries.iter() {
+        println!(
+            "{:<16} {:<64} {:<6} {:<8} moved by {} at {}",
+            entry.tag,
+            entry.digest,
+            format!("{:?}", entry.file_type).to_lowercase(),
+            format!("{:?}", entry.architecture).to_lowercase(),
+            entry.moved_by,
+            entry.moved_at
+        );
+    }
+}
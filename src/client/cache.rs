@@ -0,0 +1,288 @@
+//! Content-addressed cache of downloaded plugin files
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use chrono::{NaiveDateTime, Utc};
+use log::{info, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::{Error, Result},
+    pki::encode_hex,
+    PluginVariant,
+};
+
+/// Default maximum size of all cached files
+pub const DEFAULT_MAX_CACHE_SIZE: u64 = 512 * 1024 * 1024; // 512 mb
+
+const INDEX_FILE: &str = "index.json";
+const BLOBS_DIR: &str = "blobs";
+
+/// A cached file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    size: u64,
+    last_used: NaiveDateTime,
+}
+
+/// Persistent state of the cache
+#[derive(Default, Serialize, Deserialize)]
+struct CacheIndex {
+    /// Variant each query resolved to when the registry was last reachable
+    #[serde(default)]
+    resolved: HashMap<String, PluginVariant>,
+    /// All cached files by their digest
+    #[serde(default)]
+    entries: HashMap<String, CacheEntry>,
+}
+
+/// Cache of downloaded plugin files stored in a local directory.
+///
+/// Files are stored by their digest and are verified again on every cache hit.
+/// Once the cache exceeds its maximum size the least recently used files are removed.
+/// The cache additionally remembers which variant a plugin uri resolved to,
+/// so plugins can still be found while the registry is unreachable.
+pub struct PluginCache {
+    dir: PathBuf,
+    max_size: u64,
+    index: Mutex<CacheIndex>,
+}
+
+impl PluginCache {
+    /// Opens or creates the cache in the given directory.
+    pub fn new<P: AsRef<Path>>(dir: P, max_size: u64) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(dir.join(BLOBS_DIR))?;
+
+        let mut index = match std::fs::read(dir.join(INDEX_FILE)) {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|err| {
+                warn!("discarding unreadable cache index: {}", err);
+                CacheIndex::default()
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => CacheIndex::default(),
+            Err(err) => return Err(err.into()),
+        };
+
+        // forget files which have been removed by hand
+        let blobs = dir.join(BLOBS_DIR);
+        index
+            .entries
+            .retain(|digest, _| blobs.join(digest).is_file());
+
+        Ok(Self {
+            dir,
+            max_size,
+            index: Mutex::new(index),
+        })
+    }
+
+    /// Returns the path of the cached file or `None` if the file is not cached.
+    ///
+    /// Cached files are hashed on every access, corrupted files are removed from the cache.
+    pub fn get(&self, variant: &PluginVariant) -> Result<Option<PathBuf>> {
+        let path = self.blob_path(&variant.digest)?;
+        if !self.index.lock().entries.contains_key(&variant.digest) {
+            return Ok(None);
+        }
+
+        if file_digest(&path)? != variant.digest {
+            warn!("removing corrupted file {} from the cache", variant.digest);
+            self.index.lock().entries.remove(&variant.digest);
+            remove_file(&path)?;
+            self.save()?;
+            return Ok(None);
+        }
+
+        if let Some(entry) = self.index.lock().entries.get_mut(&variant.digest) {
+            entry.last_used = Utc::now().naive_utc();
+        }
+        self.save()?;
+        Ok(Some(path))
+    }
+
+    /// Returns the path at which the file of the variant is stored.
+    /// The file has to be verified before it is written to this path.
+    pub fn blob_path(&self, digest: &str) -> Result<PathBuf> {
+        if digest.is_empty() || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::Parse(format!("invalid digest `{}`", digest)));
+        }
+        Ok(self.dir.join(BLOBS_DIR).join(digest))
+    }
+
+    /// Adds a verified file which has been written to [`PluginCache::blob_path`]
+    /// and evicts the least recently used files in case the cache is too large.
+    pub fn insert(&self, variant: &PluginVariant) -> Result<PathBuf> {
+        let path = self.blob_path(&variant.digest)?;
+        let size = std::fs::metadata(&path)?.len();
+
+        let evicted = {
+            let mut index = self.index.lock();
+            index.entries.insert(
+                variant.digest.clone(),
+                CacheEntry {
+                    size,
+                    last_used: Utc::now().naive_utc(),
+                },
+            );
+
+            let mut entries = index
+                .entries
+                .iter()
+                .filter(|(digest, _)| **digest != variant.digest)
+                .map(|(digest, entry)| (digest.clone(), entry.clone()))
+                .collect::<Vec<_>>();
+            entries.sort_by_key(|(_, entry)| entry.last_used);
+
+            let mut total = index.entries.values().map(|entry| entry.size).sum::<u64>();
+            let mut evicted = Vec::new();
+            for (digest, entry) in entries.into_iter() {
+                if total <= self.max_size {
+                    break;
+                }
+                total -= entry.size;
+                index.entries.remove(&digest);
+                evicted.push(digest);
+            }
+            evicted
+        };
+
+        for digest in evicted.iter() {
+            info!("evicting {} from the cache", digest);
+            remove_file(&self.blob_path(digest)?)?;
+        }
+        self.save()?;
+        Ok(path)
+    }
+
+    /// Remembers the variant a query resolved to.
+    pub fn set_resolved(&self, query: &str, variant: &PluginVariant) -> Result<()> {
+        self.index
+            .lock()
+            .resolved
+            .insert(query.to_owned(), variant.clone());
+        self.save()
+    }
+
+    /// Returns the variant the query resolved to when the registry was last reachable.
+    pub fn resolved(&self, query: &str) -> Option<PluginVariant> {
+        self.index.lock().resolved.get(query).cloned()
+    }
+
+    /// Returns the total size of all cached files.
+    pub fn size(&self) -> u64 {
+        self.index
+            .lock()
+            .entries
+            .values()
+            .map(|entry| entry.size)
+            .sum()
+    }
+
+    /// Atomically writes the index to disk.
+    fn save(&self) -> Result<()> {
+        let contents = serde_json::to_vec(&*self.index.lock())?;
+        let mut temp = tempfile::NamedTempFile::new_in(&self.dir)?;
+        std::io::Write::write_all(&mut temp, &contents)?;
+        temp.persist(self.dir.join(INDEX_FILE))
+            .map_err(|err| Error::from(err.error))?;
+        Ok(())
+    }
+}
+
+/// Computes the hex encoded sha256 digest of the file.
+fn file_digest(path: &Path) -> Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let len = file.read(&mut buffer)?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buffer[..len]);
+    }
+    Ok(encode_hex(&hasher.finalize()))
+}
+
+fn remove_file(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variant(contents: &[u8]) -> PluginVariant {
+        let meta = crate::storage::database::tests::metadata(
+            &sha256::digest(contents),
+            "coredump",
+            "0.2.0",
+            1,
+            1,
+        );
+        PluginVariant {
+            digest: meta.digest,
+            signature: meta.signature,
+            key_id: None,
+            created_at: meta.created_at,
+            descriptor: meta.descriptors[0].clone(),
+            yanked_at: None,
+        }
+    }
+
+    fn insert(cache: &PluginCache, contents: &[u8]) -> PluginVariant {
+        let variant = variant(contents);
+        std::fs::write(cache.blob_path(&variant.digest).unwrap(), contents).unwrap();
+        cache.insert(&variant).unwrap();
+        variant
+    }
+
+    #[test]
+    fn eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = PluginCache::new(dir.path(), 10).unwrap();
+
+        let a = insert(&cache, b"aaaa");
+        let b = insert(&cache, b"bbbb");
+        assert_eq!(cache.size(), 8);
+
+        // using `a` makes `b` the least recently used file
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        assert!(cache.get(&a).unwrap().is_some());
+        let c = insert(&cache, b"cccc");
+        assert_eq!(cache.size(), 8);
+        assert!(cache.get(&a).unwrap().is_some());
+        assert!(cache.get(&b).unwrap().is_none());
+        assert!(!cache.blob_path(&b.digest).unwrap().exists());
+
+        // the index survives reopening the cache
+        cache.set_resolved("coredump:latest", &c).unwrap();
+        drop(cache);
+        let cache = PluginCache::new(dir.path(), 10).unwrap();
+        assert!(cache.get(&c).unwrap().is_some());
+        assert_eq!(cache.resolved("coredump:latest").unwrap().digest, c.digest);
+
+        assert!(cache.blob_path("../index.json").is_err());
+    }
+
+    #[test]
+    fn corrupted_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = PluginCache::new(dir.path(), DEFAULT_MAX_CACHE_SIZE).unwrap();
+
+        let a = insert(&cache, b"aaaa");
+        let path = cache.blob_path(&a.digest).unwrap();
+        std::fs::write(&path, b"evil").unwrap();
+        assert!(cache.get(&a).unwrap().is_none());
+        assert!(!path.exists());
+        assert_eq!(cache.size(), 0);
+    }
+}
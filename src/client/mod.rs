@@ -0,0 +1,1192 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures_util::StreamExt;
+use log::warn;
+use reqwest::{Certificate, Proxy, RequestBuilder, Response, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    error::{Error, Result},
+    pki::encode_hex,
+    rest::{
+        models::{
+            ErrorResponse, PluginTagMoveRequest, PluginTagsResponse, PluginUploadResponse,
+            PluginsFindResponse,
+        },
+        signing::verify_index,
+    },
+    storage::{database::PluginDatabaseFindParams, PluginMetadata, TagEntry},
+    PluginInfo, PluginUri, PluginVariant, PluginsAllResponse, SignatureGenerator,
+    SignatureVerifier, MEMFLOW_DEFAULT_REGISTRY, MEMFLOW_DEFAULT_REGISTRY_VERIFYING_KEY,
+};
+
+mod cache;
+pub use cache::{PluginCache, DEFAULT_MAX_CACHE_SIZE};
+
+/// Default user agent sent with every request
+const DEFAULT_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+// TODO: replace
+#[inline]
+pub(crate) fn to_http_err(err: reqwest::Error) -> Error {
+    if err.is_connect() || err.is_timeout() {
+        Error::Unreachable(err.to_string())
+    } else if let Some(status) = err.status() {
+        Error::Http(format!("status {}: {}", status, err))
+    } else {
+        Error::Http(err.to_string())
+    }
+}
+
+/// Returns the response if the request was successful or the error returned by the registry otherwise.
+async fn error_for_status(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.bytes().await.map_err(to_http_err)?;
+    Err(response_error(status, &body))
+}
+
+/// Converts the error body returned by the registry into the matching error.
+fn response_error(status: StatusCode, body: &[u8]) -> Error {
+    match serde_json::from_slice::<ErrorResponse>(body) {
+        Ok(err) => err.into(),
+        // errors which have not been created by the registry itself, e.g. by a reverse proxy
+        Err(_) => Error::Http(format!(
+            "status {}: {}",
+            status,
+            String::from_utf8_lossy(body)
+        )),
+    }
+}
+
+#[inline]
+fn parse_registry_url(registry: Option<&str>) -> Result<Url> {
+    // default to https - only allow http scheme if explicitly requested
+    let mut registry = registry.unwrap_or(MEMFLOW_DEFAULT_REGISTRY).to_owned();
+    if !registry.starts_with("http://") && !registry.starts_with("https://") {
+        registry = format!("https://{}", registry);
+    }
+    registry
+        .parse()
+        .map_err(|err| Error::Parse(format!("invalid registry url `{}`: {}", registry, err)))
+}
+
+/// Retry policy for failed requests.
+///
+/// Only idempotent requests are retried, and only when the connection failed, timed out
+/// or the registry responded with `429 Too Many Requests` or a server error.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Maximum number of retries after the initial attempt
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled after every attempt
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between two attempts
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Never retries failed requests.
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the delay before the given retry.
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+#[inline]
+fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Builder for a [`RegistryClient`]
+#[derive(Default)]
+pub struct RegistryClientBuilder {
+    registry: Option<String>,
+    token: Option<String>,
+    http_client: Option<reqwest::Client>,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    user_agent: Option<String>,
+    proxy: Option<Proxy>,
+    root_certificates: Vec<Certificate>,
+    retry: RetryPolicy,
+    verifier: Option<SignatureVerifier>,
+    index_verifier: Option<SignatureVerifier>,
+    cache: Option<PluginCache>,
+    offline: bool,
+}
+
+impl RegistryClientBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the registry url, defaults to [`MEMFLOW_DEFAULT_REGISTRY`].
+    pub fn with_registry(mut self, registry: &str) -> Self {
+        self.registry = Some(registry.to_owned());
+        self
+    }
+
+    /// Sets the bearer token which is sent with all requests that require authentication.
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_owned());
+        self
+    }
+
+    /// Uses an existing http client, e.g. to share its connection pool with other parts of an application.
+    ///
+    /// The timeouts, user agent, proxy and root certificates of the builder are ignored in this case.
+    pub fn with_http_client(mut self, http_client: reqwest::Client) -> Self {
+        self.http_client = Some(http_client);
+        self
+    }
+
+    /// Sets the timeout of an entire request including the response body.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = Some(user_agent.to_owned());
+        self
+    }
+
+    pub fn with_proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Trusts an additional root certificate, e.g. for registries using a private certificate authority.
+    pub fn with_root_certificate(mut self, certificate: Certificate) -> Self {
+        self.root_certificates.push(certificate);
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sets the key which verifies the signatures of downloaded files,
+    /// defaults to [`MEMFLOW_DEFAULT_REGISTRY_VERIFYING_KEY`].
+    pub fn with_verifier(mut self, verifier: SignatureVerifier) -> Self {
+        self.verifier = Some(verifier);
+        self
+    }
+
+    /// Only trusts index responses which have been signed by the given key of the registry
+    /// and have not expired yet, see [`crate::rest::signing`].
+    pub fn with_index_verifier(mut self, verifier: SignatureVerifier) -> Self {
+        self.index_verifier = Some(verifier);
+        self
+    }
+
+    /// Keeps downloaded files in the cache, see [`RegistryClient::pull`].
+    pub fn with_cache(mut self, cache: PluginCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Never contacts the registry when pulling plugins and only uses the cache instead.
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    pub fn build(self) -> Result<RegistryClient> {
+        let registry = parse_registry_url(self.registry.as_deref())?;
+
+        let http_client = match self.http_client {
+            Some(http_client) => http_client,
+            None => {
+                let mut builder = reqwest::Client::builder().user_agent(
+                    self.user_agent
+                        .unwrap_or_else(|| DEFAULT_USER_AGENT.to_owned()),
+                );
+                if let Some(timeout) = self.timeout {
+                    builder = builder.timeout(timeout);
+                }
+                if let Some(timeout) = self.connect_timeout {
+                    builder = builder.connect_timeout(timeout);
+                }
+                if let Some(proxy) = self.proxy {
+                    builder = builder.proxy(proxy);
+                }
+                for certificate in self.root_certificates.into_iter() {
+                    builder = builder.add_root_certificate(certificate);
+                }
+                builder.build().map_err(to_http_err)?
+            }
+        };
+
+        let verifier = match self.verifier {
+            Some(verifier) => verifier,
+            None => SignatureVerifier::with_str(MEMFLOW_DEFAULT_REGISTRY_VERIFYING_KEY)?,
+        };
+
+        Ok(RegistryClient {
+            registry,
+            token: self.token,
+            http_client,
+            retry: self.retry,
+            verifier,
+            index_verifier: self.index_verifier,
+            cache: self.cache.map(Arc::new),
+            offline: self.offline,
+        })
+    }
+}
+
+/// Client for the rest api of a registry.
+///
+/// The client is cheap to clone, all clones share the same connection pool and settings.
+#[derive(Clone)]
+pub struct RegistryClient {
+    registry: Url,
+    token: Option<String>,
+    http_client: reqwest::Client,
+    retry: RetryPolicy,
+    verifier: SignatureVerifier,
+    index_verifier: Option<SignatureVerifier>,
+    cache: Option<Arc<PluginCache>>,
+    offline: bool,
+}
+
+impl RegistryClient {
+    /// Creates a client with the default settings for the given registry
+    /// or [`MEMFLOW_DEFAULT_REGISTRY`] if no registry is specified.
+    pub fn new(registry: Option<&str>) -> Result<Self> {
+        let mut builder = RegistryClientBuilder::new();
+        if let Some(registry) = registry {
+            builder = builder.with_registry(registry);
+        }
+        builder.build()
+    }
+
+    pub fn builder() -> RegistryClientBuilder {
+        RegistryClientBuilder::new()
+    }
+
+    #[inline]
+    pub fn registry(&self) -> &Url {
+        &self.registry
+    }
+
+    /// Returns the url of the given path on the registry.
+    fn url(&self, path: &str) -> Url {
+        let mut url = self.registry.clone();
+        url.set_path(path);
+        url
+    }
+
+    /// Adds the bearer token to a request which requires authentication.
+    fn authed(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    /// Sends an idempotent request and retries it according to the retry policy.
+    async fn send(&self, mut request: RequestBuilder) -> Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let retry = if attempt < self.retry.max_retries {
+                request.try_clone()
+            } else {
+                None
+            };
+
+            let result = request.send().await;
+            let Some(retry) = retry else {
+                return error_for_status(result.map_err(to_http_err)?).await;
+            };
+            match result {
+                Ok(response) if !is_retryable_status(response.status()) => {
+                    return error_for_status(response).await
+                }
+                Ok(response) => warn!(
+                    "request to {} failed with status {}, retrying",
+                    response.url(),
+                    response.status()
+                ),
+                Err(err) if err.is_connect() || err.is_timeout() => {
+                    warn!("request failed: {}, retrying", err)
+                }
+                Err(err) => return Err(to_http_err(err)),
+            }
+
+            tokio::time::sleep(self.retry.backoff(attempt)).await;
+            attempt += 1;
+            request = retry;
+        }
+    }
+
+    /// Sends a request to an index route and parses the response.
+    /// The signature of the response is verified if an index verifier is set.
+    async fn index<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T> {
+        // the signature has to cover the requested path, the response might have been redirected elsewhere
+        let request = request.build().map_err(to_http_err)?;
+        let path_and_query = match request.url().query() {
+            Some(query) => format!("{}?{}", request.url().path(), query),
+            None => request.url().path().to_owned(),
+        };
+
+        let response = self
+            .send(RequestBuilder::from_parts(
+                self.http_client.clone(),
+                request,
+            ))
+            .await?;
+        let headers = response.headers().clone();
+        let body = response.bytes().await.map_err(to_http_err)?;
+
+        if let Some(verifier) = &self.index_verifier {
+            verify_index(verifier, &path_and_query, &headers, &body)?;
+        }
+
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Sends a request which must not be repeated.
+    ///
+    /// Retrying a request whose response got lost could repeat its side effects,
+    /// e.g. a second delete removes a file from the trash permanently.
+    async fn send_once(&self, request: RequestBuilder) -> Result<Response> {
+        let response = request.send().await.map_err(to_http_err)?;
+        error_for_status(response).await
+    }
+
+    /// Checks if the registry is reachable and healthy, failed requests are not retried.
+    pub async fn health(&self) -> Result<()> {
+        let response = self
+            .http_client
+            .get(self.url("health"))
+            .send()
+            .await
+            .map_err(to_http_err)?;
+        error_for_status(response).await?;
+        Ok(())
+    }
+
+    /// Retrieves a list of all plugins and their descriptions.
+    pub async fn plugins(&self) -> Result<Vec<PluginInfo>> {
+        let response = self
+            .index::<PluginsAllResponse>(self.http_client.get(self.url("plugins")))
+            .await?;
+        Ok(response.plugins)
+    }
+
+    /// Retrieves the latest variants of a plugin, by default only for the current os and architecture.
+    pub async fn plugin_versions(
+        &self,
+        plugin_name: &str,
+        all_archs: bool,
+        memflow_plugin_version: Option<i32>,
+        limit: usize,
+    ) -> Result<Vec<PluginVariant>> {
+        let mut path = self.url(&format!("plugins/{}", plugin_name));
+
+        // setup filtering based on the os memflowup is built for
+        {
+            let mut query = path.query_pairs_mut();
+
+            if let Some(memflow_plugin_version) = memflow_plugin_version {
+                query.append_pair(
+                    "memflow_plugin_version",
+                    &memflow_plugin_version.to_string(),
+                );
+            }
+
+            query.append_pair("limit", &limit.to_string());
+        }
+        if !all_archs {
+            append_os_arch_filter(&mut path);
+        }
+
+        let response = self
+            .index::<PluginsFindResponse>(self.http_client.get(path))
+            .await?;
+        Ok(response.plugins)
+    }
+
+    /// Retrieves the variants of a plugin matching the given search parameters.
+    pub async fn find_variants(
+        &self,
+        plugin_name: &str,
+        params: &PluginDatabaseFindParams,
+    ) -> Result<Vec<PluginVariant>> {
+        let request = self
+            .http_client
+            .get(self.url(&format!("plugins/{}", plugin_name)))
+            .query(params);
+        let response = self.index::<PluginsFindResponse>(request).await?;
+        Ok(response.plugins)
+    }
+
+    /// Finds the latest variant matching the uri.
+    ///
+    /// The registry of the uri is ignored, the variant is always looked up in the registry of this client.
+    pub async fn find_by_uri(
+        &self,
+        plugin_uri: &PluginUri,
+        all_archs: bool,
+        memflow_plugin_version: Option<i32>,
+    ) -> Result<PluginVariant> {
+        let mut path = self.url(&format!("plugins/{}", plugin_uri.image()));
+
+        // setup filtering based on the os memflowup is built for
+        {
+            let mut query = path.query_pairs_mut();
+            if plugin_uri.version() != "latest" {
+                query.append_pair("version", plugin_uri.version());
+            }
+
+            if let Some(memflow_plugin_version) = memflow_plugin_version {
+                query.append_pair(
+                    "memflow_plugin_version",
+                    &memflow_plugin_version.to_string(),
+                );
+            }
+
+            // limit to the latest entry
+            query.append_pair("limit", "1");
+        }
+        if !all_archs {
+            append_os_arch_filter(&mut path);
+        }
+
+        let response = self
+            .index::<PluginsFindResponse>(self.http_client.get(path))
+            .await?;
+
+        if let Some(variant) = response.plugins.first() {
+            Ok(variant.to_owned())
+        } else {
+            Err(Error::NotFound(format!(
+                "plugin `{}` not found for the current architecture",
+                plugin_uri
+            )))
+        }
+    }
+
+    /// Finds the latest variant matching the uri and returns it together with the path of its file in the cache.
+    ///
+    /// Files are only downloaded in case they are not cached yet.
+    /// While the registry is unreachable or the client is offline
+    /// the uri is resolved to the variant it resolved to the last time instead.
+    pub async fn pull(
+        &self,
+        plugin_uri: &PluginUri,
+        all_archs: bool,
+        memflow_plugin_version: Option<i32>,
+    ) -> Result<(PluginVariant, PathBuf)> {
+        let cache = self
+            .cache
+            .as_ref()
+            .ok_or_else(|| Error::NotImplemented("the client does not have a cache".to_owned()))?;
+
+        let query = format!(
+            "{}/{}:{} all_archs={} memflow_plugin_version={:?}",
+            self.registry.as_str().trim_end_matches('/'),
+            plugin_uri.image(),
+            plugin_uri.version(),
+            all_archs,
+            memflow_plugin_version
+        );
+        let variant = if self.offline {
+            cache
+                .resolved(&query)
+                .ok_or_else(|| Error::NotFound(format!("plugin `{}` is not cached", plugin_uri)))?
+        } else {
+            match self
+                .find_by_uri(plugin_uri, all_archs, memflow_plugin_version)
+                .await
+            {
+                Ok(variant) => {
+                    cache.set_resolved(&query, &variant)?;
+                    variant
+                }
+                Err(Error::Unreachable(err)) => {
+                    warn!("registry is unreachable, using cached index: {}", err);
+                    cache.resolved(&query).ok_or(Error::Unreachable(err))?
+                }
+                Err(err) => return Err(err),
+            }
+        };
+
+        if let Some(path) = cache.get(&variant)? {
+            return Ok((variant, path));
+        }
+        if self.offline {
+            return Err(Error::NotFound(format!(
+                "file {} is not cached",
+                variant.digest
+            )));
+        }
+
+        self.download_to_file(&variant, cache.blob_path(&variant.digest)?)
+            .await?;
+        let path = cache.insert(&variant)?;
+        Ok((variant, path))
+    }
+
+    /// Starts downloading the file with the given digest.
+    pub async fn download(&self, digest: &str) -> Result<Response> {
+        self.send(self.http_client.get(self.url(&format!("files/{}", digest))))
+            .await
+    }
+
+    /// Downloads the file with the given digest into memory.
+    pub async fn download_by_digest(&self, digest: &str) -> Result<Bytes> {
+        self.download(digest)
+            .await?
+            .bytes()
+            .await
+            .map_err(to_http_err)
+    }
+
+    /// Downloads the file of the variant into memory and verifies its digest and signature.
+    pub async fn download_verified(&self, variant: &PluginVariant) -> Result<Bytes> {
+        let bytes = self.download_by_digest(&variant.digest).await?;
+        let mut hasher = Sha256::new();
+        hasher.update(&bytes);
+        self.verify(variant, hasher, Some(&bytes))?;
+        Ok(bytes)
+    }
+
+    /// Downloads the file of the variant to the given path.
+    ///
+    /// The file is written into a temporary file next to the destination first
+    /// and is only moved into place once its digest and signature have been verified.
+    pub async fn download_to_file<P: AsRef<Path>>(
+        &self,
+        variant: &PluginVariant,
+        path: P,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let temp = NamedTempFile::new_in(dir)?;
+        let mut file = tokio::fs::File::from_std(temp.reopen()?);
+
+        let mut hasher = Sha256::new();
+        let mut stream = self.download(&variant.digest).await?.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(to_http_err)?;
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        file.sync_all().await?;
+
+        // signatures which can not be verified from the digest require the entire file
+        let contents = if self.verifier.scheme().supports_digest() {
+            None
+        } else {
+            Some(tokio::fs::read(temp.path()).await?)
+        };
+        self.verify(variant, hasher, contents.as_deref())?;
+        temp.persist(path).map_err(|err| Error::from(err.error))?;
+        Ok(())
+    }
+
+    /// Ensures the downloaded contents match the digest and signature of the variant.
+    /// The signature is verified against the contents if they are given and against the digest otherwise.
+    fn verify(
+        &self,
+        variant: &PluginVariant,
+        hasher: Sha256,
+        contents: Option<&[u8]>,
+    ) -> Result<()> {
+        let digest = encode_hex(&hasher.clone().finalize());
+        if digest != variant.digest {
+            return Err(Error::Signature(format!(
+                "downloaded file has digest {} instead of {}",
+                digest, variant.digest
+            )));
+        }
+        match contents {
+            Some(contents) => self.verifier.is_valid(contents, &variant.signature),
+            None => self.verifier.is_valid_digest(hasher, &variant.signature),
+        }
+    }
+
+    /// Retrieves the metadata of the file with the given digest.
+    pub async fn metadata(&self, digest: &str) -> Result<PluginMetadata> {
+        self.index(
+            self.http_client
+                .get(self.url(&format!("files/{}/metadata", digest))),
+        )
+        .await
+    }
+
+    /// Signs and uploads the file.
+    pub async fn upload<P: AsRef<Path>>(
+        &self,
+        file_path: P,
+        generator: &mut SignatureGenerator,
+    ) -> Result<PluginUploadResponse> {
+        // read file
+        let file_content = tokio::fs::read(&file_path).await?;
+
+        // sign payload
+        let signature = generator.sign(&file_content[..])?;
+
+        self.upload_signed(file_path, file_content, &signature)
+            .await
+    }
+
+    /// Uploads a file that has already been signed, e.g. on another machine.
+    pub async fn upload_with_signature<P: AsRef<Path>>(
+        &self,
+        file_path: P,
+        signature: &str,
+    ) -> Result<PluginUploadResponse> {
+        let file_content = tokio::fs::read(&file_path).await?;
+        self.upload_signed(file_path, file_content, signature).await
+    }
+
+    async fn upload_signed<P: AsRef<Path>>(
+        &self,
+        file_path: P,
+        file_content: Vec<u8>,
+        signature: &str,
+    ) -> Result<PluginUploadResponse> {
+        // setup form
+        let mut form = reqwest::multipart::Form::new();
+        let file_name = file_path
+            .as_ref()
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        let file_part = reqwest::multipart::Part::bytes(file_content)
+            .file_name(file_name)
+            .mime_str("application/octet-stream")
+            .unwrap();
+        form = form.part("file", file_part);
+        form = form.text("signature", signature.to_owned());
+
+        // uploads are not retried as the form cannot be sent twice
+        let request = self
+            .authed(self.http_client.post(self.url("files")))
+            .multipart(form);
+        self.send_once(request)
+            .await?
+            .json::<PluginUploadResponse>()
+            .await
+            .map_err(to_http_err)
+    }
+
+    /// Deletes a file from the registry.
+    pub async fn delete(&self, file_digest: &str) -> Result<String> {
+        let request = self.authed(
+            self.http_client
+                .delete(self.url(&format!("files/{}", file_digest))),
+        );
+        self.send_once(request)
+            .await?
+            .text()
+            .await
+            .map_err(to_http_err)
+    }
+
+    /// Retrieves all tags of a plugin and the files they currently point to.
+    pub async fn tags(&self, plugin_name: &str) -> Result<Vec<TagEntry>> {
+        let response = self
+            .index::<PluginTagsResponse>(
+                self.http_client
+                    .get(self.url(&format!("plugins/{}/tags", plugin_name))),
+            )
+            .await?;
+        Ok(response.tags)
+    }
+
+    /// Moves a tag of a plugin to the file with the given digest.
+    pub async fn move_tag(
+        &self,
+        plugin_name: &str,
+        tag: &str,
+        digest: &str,
+    ) -> Result<Vec<TagEntry>> {
+        let request = self
+            .authed(
+                self.http_client
+                    .put(self.url(&format!("plugins/{}/tags/{}", plugin_name, tag))),
+            )
+            .json(&PluginTagMoveRequest {
+                digest: digest.to_owned(),
+            });
+        let response = self
+            .send_once(request)
+            .await?
+            .json::<PluginTagsResponse>()
+            .await
+            .map_err(to_http_err)?;
+        Ok(response.tags)
+    }
+}
+
+/// Retrieves a list of all plugins and their descriptions.
+pub async fn plugins(registry: Option<&str>) -> Result<Vec<PluginInfo>> {
+    RegistryClient::new(registry)?.plugins().await
+}
+
+pub async fn plugin_versions(
+    registry: Option<&str>,
+    plugin_name: &str,
+    all_archs: bool,
+    memflow_plugin_version: Option<i32>,
+    limit: usize,
+) -> Result<Vec<PluginVariant>> {
+    RegistryClient::new(registry)?
+        .plugin_versions(plugin_name, all_archs, memflow_plugin_version, limit)
+        .await
+}
+
+/// Retrieves the variants of a plugin matching the given search parameters.
+pub async fn find_variants(
+    registry: Option<&str>,
+    plugin_name: &str,
+    params: &PluginDatabaseFindParams,
+) -> Result<Vec<PluginVariant>> {
+    RegistryClient::new(registry)?
+        .find_variants(plugin_name, params)
+        .await
+}
+
+// Downloads a plugin based on the specified uri
+pub async fn find_by_uri(
+    plugin_uri: &PluginUri,
+    all_archs: bool,
+    memflow_plugin_version: Option<i32>,
+) -> Result<PluginVariant> {
+    RegistryClient::new(Some(plugin_uri.registry()))?
+        .find_by_uri(plugin_uri, all_archs, memflow_plugin_version)
+        .await
+}
+
+pub async fn download(plugin_uri: &PluginUri, variant: &PluginVariant) -> Result<Response> {
+    RegistryClient::new(Some(plugin_uri.registry()))?
+        .download(&variant.digest)
+        .await
+}
+
+/// Downloads the variant to the given path after verifying it with the default registry key.
+pub async fn download_to_file<P: AsRef<Path>>(
+    plugin_uri: &PluginUri,
+    variant: &PluginVariant,
+    path: P,
+) -> Result<()> {
+    RegistryClient::new(Some(plugin_uri.registry()))?
+        .download_to_file(variant, path)
+        .await
+}
+
+pub async fn metadata(plugin_uri: &PluginUri, variant: &PluginVariant) -> Result<PluginMetadata> {
+    RegistryClient::new(Some(plugin_uri.registry()))?
+        .metadata(&variant.digest)
+        .await
+}
+
+/// Retrieves the metadata of the file with the given digest.
+pub async fn metadata_by_digest(registry: Option<&str>, digest: &str) -> Result<PluginMetadata> {
+    RegistryClient::new(registry)?.metadata(digest).await
+}
+
+/// Downloads the file with the given digest into memory.
+pub async fn download_by_digest(registry: Option<&str>, digest: &str) -> Result<Bytes> {
+    RegistryClient::new(registry)?
+        .download_by_digest(digest)
+        .await
+}
+
+pub async fn upload<P: AsRef<Path>>(
+    registry: Option<&str>,
+    token: Option<&str>,
+    file_path: P,
+    generator: &mut SignatureGenerator,
+) -> Result<PluginUploadResponse> {
+    client_with_token(registry, token)?
+        .upload(file_path, generator)
+        .await
+}
+
+/// Deletes a file from the registry
+pub async fn delete(
+    registry: Option<&str>,
+    token: Option<&str>,
+    file_digest: &str,
+) -> Result<String> {
+    client_with_token(registry, token)?
+        .delete(file_digest)
+        .await
+}
+
+fn client_with_token(registry: Option<&str>, token: Option<&str>) -> Result<RegistryClient> {
+    let mut builder = RegistryClient::builder();
+    if let Some(registry) = registry {
+        builder = builder.with_registry(registry);
+    }
+    if let Some(token) = token {
+        builder = builder.with_token(token);
+    }
+    builder.build()
+}
+
+fn append_os_arch_filter(path: &mut Url) {
+    let mut query = path.query_pairs_mut();
+
+    // file_type
+    #[cfg(target_os = "windows")]
+    query.append_pair("file_type", "pe");
+    #[cfg(target_os = "linux")]
+    query.append_pair("file_type", "elf");
+    #[cfg(target_os = "macos")]
+    query.append_pair("file_type", "mach");
+
+    // architecture
+    #[cfg(target_arch = "x86_64")]
+    query.append_pair("architecture", "x86_64");
+    #[cfg(target_arch = "x86")]
+    query.append_pair("architecture", "x86");
+    #[cfg(target_arch = "aarch64")]
+    query.append_pair("architecture", "arm64");
+    #[cfg(target_arch = "arm")]
+    query.append_pair("architecture", "arm");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use axum::{extract::State, routing::get, Json, Router};
+
+    use crate::storage::database::tests::{
+        plugin_contents, put_plugin, put_signed_plugin, serve, serve_router,
+    };
+
+    use super::*;
+
+    /// Serves `/plugins` which fails with the given status for the first `failures` requests
+    async fn serve_flaky(failures: usize, status: StatusCode) -> (String, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route(
+                "/plugins",
+                get(move |State(requests): State<Arc<AtomicUsize>>| async move {
+                    if requests.fetch_add(1, Ordering::SeqCst) < failures {
+                        Err((status, "unavailable"))
+                    } else {
+                        Ok(Json(PluginsAllResponse {
+                            plugins: Vec::new(),
+                        }))
+                    }
+                }),
+            )
+            .with_state(requests.clone());
+
+        let (registry, _) = serve_router(app).await;
+        (registry, requests)
+    }
+
+    fn client(registry: &str, max_retries: u32) -> RegistryClient {
+        RegistryClient::builder()
+            .with_registry(registry)
+            .with_timeout(Duration::from_secs(10))
+            .with_retry_policy(RetryPolicy {
+                max_retries,
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(10),
+            })
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn retry() {
+        let (registry, requests) = serve_flaky(2, StatusCode::SERVICE_UNAVAILABLE).await;
+        assert!(client(&registry, 2).plugins().await.unwrap().is_empty());
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // gives up once all retries failed
+        let (registry, requests) = serve_flaky(2, StatusCode::SERVICE_UNAVAILABLE).await;
+        assert!(matches!(
+            client(&registry, 1).plugins().await,
+            Err(Error::Http(_))
+        ));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // client errors are never retried
+        let (registry, requests) = serve_flaky(1, StatusCode::BAD_REQUEST).await;
+        assert!(client(&registry, 2).plugins().await.is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn verified_download() {
+        use crate::pki::tests::key_pair;
+        use crate::storage::{backend::MemoryStore, Storage};
+
+        let (mut alice, alice_pem) = key_pair(1);
+        let (_, bob_pem) = key_pair(2);
+
+        let backend = MemoryStore::new();
+        let digest = put_signed_plugin(&backend, "coredump", "0.2.0", &mut alice).await;
+        let bytes = plugin_contents("coredump", "0.2.0");
+        let storage = Storage::with_backend(backend).await.unwrap();
+        let meta = storage.metadata(&digest).await.unwrap();
+        let registry = serve(storage).await;
+
+        let variant = |digest: &str| PluginVariant {
+            digest: digest.to_owned(),
+            signature: meta.signature.clone(),
+            key_id: None,
+            created_at: meta.created_at,
+            descriptor: meta.descriptors[0].clone(),
+            yanked_at: None,
+        };
+        let client = |pem: &str| {
+            RegistryClient::builder()
+                .with_registry(&registry)
+                .with_verifier(SignatureVerifier::with_str(pem).unwrap())
+                .build()
+                .unwrap()
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("libmemflow_coredump.so");
+        client(&alice_pem)
+            .download_to_file(&variant(&digest), &path)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), &bytes[..]);
+        assert_eq!(
+            client(&alice_pem)
+                .download_verified(&variant(&digest))
+                .await
+                .unwrap(),
+            bytes
+        );
+
+        // files signed by another key are never moved into place
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            client(&bob_pem)
+                .download_to_file(&variant(&digest), &path)
+                .await,
+            Err(Error::Signature(_))
+        ));
+        assert!(matches!(
+            client(&bob_pem).download_verified(&variant(&digest)).await,
+            Err(Error::Signature(_))
+        ));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        // unknown files are not found
+        assert!(matches!(
+            client(&alice_pem)
+                .download_to_file(&variant("abcdef"), &path)
+                .await,
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn signed_index() {
+        use crate::pki::tests::key_pair;
+        use crate::rest::{
+            middlewares::ApiTokens,
+            routes::app,
+            signing::{sign_index, IndexSigner},
+        };
+        use crate::storage::{backend::MemoryStore, Storage};
+
+        let (registry_key, registry_pem) = key_pair(1);
+        let (_, mallory_pem) = key_pair(2);
+
+        let backend = MemoryStore::new();
+        let digest = put_plugin(&backend, "coredump", "0.2.0").await;
+        let storage = Storage::with_backend(backend).await.unwrap();
+        let (signed, _) = serve_router(
+            app(storage.clone(), ApiTokens::default(), Default::default()).route_layer(
+                axum::middleware::from_fn_with_state(IndexSigner::new(registry_key), sign_index),
+            ),
+        )
+        .await;
+        let unsigned = serve(storage).await;
+
+        let client = |registry: &str, pem: &str| {
+            RegistryClient::builder()
+                .with_registry(registry)
+                .with_index_verifier(SignatureVerifier::with_str(pem).unwrap())
+                .with_retry_policy(RetryPolicy::none())
+                .build()
+                .unwrap()
+        };
+        let uri = PluginUri::new("coredump:0.2.0").unwrap();
+
+        let client_ok = client(&signed, &registry_pem);
+        assert_eq!(client_ok.plugins().await.unwrap().len(), 1);
+        let variant = client_ok.find_by_uri(&uri, true, None).await.unwrap();
+        assert_eq!(variant.digest, digest);
+        assert_eq!(
+            client_ok
+                .find_variants("coredump", &Default::default())
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(client_ok.metadata(&digest).await.unwrap().digest, digest);
+        assert!(client_ok.tags("coredump").await.unwrap().is_empty());
+
+        // responses signed by another key or not signed at all are rejected
+        assert!(matches!(
+            client(&signed, &mallory_pem)
+                .find_by_uri(&uri, true, None)
+                .await,
+            Err(Error::Signature(_))
+        ));
+        assert!(matches!(
+            client(&unsigned, &registry_pem)
+                .find_by_uri(&uri, true, None)
+                .await,
+            Err(Error::Signature(_))
+        ));
+        assert!(matches!(
+            client(&unsigned, &registry_pem).metadata(&digest).await,
+            Err(Error::Signature(_))
+        ));
+
+        // signed responses for other paths are rejected even if the client is redirected to them
+        let target = format!("{}/plugins/coredump", signed);
+        let (redirect, _) = serve_router(axum::Router::new().route(
+            "/plugins/{plugin_name}",
+            axum::routing::get(move || async move { axum::response::Redirect::temporary(&target) }),
+        ))
+        .await;
+        assert!(matches!(
+            client(&redirect, &registry_pem)
+                .find_variants("qemu", &Default::default())
+                .await,
+            Err(Error::Signature(_))
+        ));
+
+        // clients without an index verifier accept unsigned responses
+        assert!(RegistryClient::new(Some(&unsigned))
+            .unwrap()
+            .find_by_uri(&uri, true, None)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn cached_pull() {
+        use crate::pki::tests::key_pair;
+        use crate::rest::{middlewares::ApiTokens, routes::app};
+        use crate::storage::{backend::MemoryStore, Storage};
+
+        let (mut alice, alice_pem) = key_pair(1);
+
+        let backend = MemoryStore::new();
+        let digest = put_signed_plugin(&backend, "coredump", "0.2.0", &mut alice).await;
+        let bytes = plugin_contents("coredump", "0.2.0");
+        let storage = Storage::with_backend(backend).await.unwrap();
+        let (registry, server) =
+            serve_router(app(storage, ApiTokens::default(), Default::default())).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let client = |offline: bool| {
+            RegistryClient::builder()
+                .with_registry(&registry)
+                .with_verifier(SignatureVerifier::with_str(&alice_pem).unwrap())
+                .with_retry_policy(RetryPolicy::none())
+                .with_cache(PluginCache::new(dir.path(), DEFAULT_MAX_CACHE_SIZE).unwrap())
+                .with_offline(offline)
+                .build()
+                .unwrap()
+        };
+        let uri: PluginUri = "coredump:0.2.0".parse().unwrap();
+
+        // nothing has been resolved yet
+        assert!(matches!(
+            client(true).pull(&uri, true, None).await,
+            Err(Error::NotFound(_))
+        ));
+
+        let (variant, path) = client(false).pull(&uri, true, None).await.unwrap();
+        assert_eq!(variant.digest, digest);
+        assert_eq!(std::fs::read(&path).unwrap(), &bytes[..]);
+
+        // the last resolution is used while offline or when the registry is gone
+        let (variant, cached) = client(true).pull(&uri, true, None).await.unwrap();
+        assert_eq!((variant.digest.as_str(), &cached), (digest.as_str(), &path));
+        server.abort();
+        let _ = server.await;
+        let (variant, cached) = client(false).pull(&uri, true, None).await.unwrap();
+        assert_eq!((variant.digest.as_str(), &cached), (digest.as_str(), &path));
+
+        // other queries are not cached
+        let uri: PluginUri = "coredump:0.1.0".parse().unwrap();
+        assert!(matches!(
+            client(false).pull(&uri, true, None).await,
+            Err(Error::Unreachable(_))
+        ));
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(0), Duration::from_millis(500));
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(10), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn registry_url() {
+        let client = RegistryClient::new(Some("registry.memflow.xyz")).unwrap();
+        assert_eq!(
+            client.url("plugins").as_str(),
+            "https://registry.memflow.xyz/plugins"
+        );
+        assert!(RegistryClient::new(Some("http://[invalid")).is_err());
+    }
+}
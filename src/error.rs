@@ -1,8 +1,16 @@
 //! Error definitions
 
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::rest::models::{ErrorCode, ErrorResponse};
+
 /// Library result type
 pub type Result<T> = std::result::Result<T, Error>;
-pub type ResponseResult<T> = std::result::Result<T, (axum::http::StatusCode, String)>;
+pub type ResponseResult<T> = std::result::Result<T, ApiError>;
 
 /// Library errors
 #[derive(thiserror::Error, Debug, Clone, PartialEq)]
@@ -21,6 +29,20 @@ pub enum Error {
     AlreadyExists(String),
     #[error("Not implemented: {0}")]
     NotImplemented(String),
+    #[error("Too large: {0}")]
+    TooLarge(String),
+
+    // Rest api errors
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    #[error("Rate limited: {0}")]
+    RateLimited(String),
+    #[error("Unreachable: {0}")]
+    Unreachable(String),
 
     // External crate error forwards
     #[error("Memflow error: {0}")]
@@ -29,6 +51,10 @@ pub enum Error {
     Http(String),
     #[error("Signature error: {0}")]
     Signature(String),
+    #[error("Object store error: {0}")]
+    ObjectStore(String),
+    #[error("Database error: {0}")]
+    Database(String),
 
     #[error("{0} {1}")]
     Wrapped(String, Box<Error>),
@@ -41,6 +67,99 @@ impl Error {
     }
 }
 
+/// Error returned by the rest api, the body is serialized as [`ErrorResponse`]
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub body: ErrorResponse,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            body: ErrorResponse {
+                code: ErrorCode::from_status(status),
+                message: message.into(),
+                details: None,
+            },
+        }
+    }
+
+    /// Attaches additional machine-readable information to the error.
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.body.details = Some(details);
+        self
+    }
+}
+
+impl From<Error> for ApiError {
+    /// Maps library errors to the matching status code.
+    ///
+    /// Rejected signatures and files the analyzer could not parse are marked with a `reason` in the details.
+    fn from(err: Error) -> Self {
+        let message = err.to_string();
+        let mut inner = &err;
+        while let Error::Wrapped(_, err) = inner {
+            inner = err;
+        }
+        match inner {
+            Error::NotFound(_) => Self::new(StatusCode::NOT_FOUND, message),
+            Error::AlreadyExists(_) => Self::new(StatusCode::CONFLICT, message),
+            Error::TooLarge(_) => Self::new(StatusCode::PAYLOAD_TOO_LARGE, message),
+            Error::Parse(_) | Error::BadRequest(_) => Self::new(StatusCode::BAD_REQUEST, message),
+            Error::Unauthorized(_) => Self::new(StatusCode::UNAUTHORIZED, message),
+            Error::Forbidden(_) => Self::new(StatusCode::FORBIDDEN, message),
+            Error::RateLimited(_) => Self::new(StatusCode::TOO_MANY_REQUESTS, message),
+            Error::Signature(_) => Self::new(StatusCode::BAD_REQUEST, message)
+                .with_details(serde_json::json!({ "reason": "bad_signature" })),
+            Error::Memflow(_) => Self::new(StatusCode::BAD_REQUEST, message)
+                .with_details(serde_json::json!({ "reason": "analyzer" })),
+            Error::NotImplemented(_) => Self::new(StatusCode::NOT_IMPLEMENTED, message),
+            Error::Unreachable(_) => Self::new(StatusCode::BAD_GATEWAY, message),
+            _ => Self::new(StatusCode::INTERNAL_SERVER_ERROR, message),
+        }
+    }
+}
+
+impl From<axum::extract::multipart::MultipartError> for ApiError {
+    fn from(err: axum::extract::multipart::MultipartError) -> Self {
+        Self::new(err.status(), err.body_text())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self.body)).into_response()
+    }
+}
+
+impl From<ErrorResponse> for Error {
+    fn from(err: ErrorResponse) -> Self {
+        // the reason tells apart errors with the same code, e.g. an invalid signature and an invalid binary
+        let reason = err
+            .details
+            .as_ref()
+            .and_then(|details| details.get("reason"))
+            .and_then(|reason| reason.as_str());
+        let message = match reason {
+            Some(reason) => format!("{} ({})", err.message, reason),
+            None => err.message,
+        };
+
+        match err.code {
+            ErrorCode::BadRequest => Error::BadRequest(message),
+            ErrorCode::Unauthorized => Error::Unauthorized(message),
+            ErrorCode::Forbidden => Error::Forbidden(message),
+            ErrorCode::NotFound => Error::NotFound(message),
+            ErrorCode::AlreadyExists => Error::AlreadyExists(message),
+            ErrorCode::TooLarge => Error::TooLarge(message),
+            ErrorCode::RateLimited => Error::RateLimited(message),
+            ErrorCode::Internal => Error::Http(message),
+        }
+    }
+}
+
 pub trait ResultExt<T> {
     fn context(self, context: &str) -> Result<T>;
 }
@@ -73,6 +192,12 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<tokio::task::JoinError> for Error {
+    fn from(err: tokio::task::JoinError) -> Self {
+        Error::Unknown(err.to_string())
+    }
+}
+
 impl From<std::str::Utf8Error> for Error {
     fn from(value: std::str::Utf8Error) -> Self {
         Error::Parse(format!("Unable to parse utf8: {}", value))
@@ -85,6 +210,27 @@ impl From<memflow::error::Error> for Error {
     }
 }
 
+impl From<object_store::Error> for Error {
+    fn from(err: object_store::Error) -> Self {
+        match err {
+            object_store::Error::NotFound { path, .. } => {
+                Error::NotFound(format!("object `{}` was not found", path))
+            }
+            object_store::Error::AlreadyExists { path, .. }
+            | object_store::Error::Precondition { path, .. } => {
+                Error::AlreadyExists(format!("object `{}` has been modified concurrently", path))
+            }
+            err => Error::ObjectStore(err.to_string()),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Database(err.to_string())
+    }
+}
+
 impl From<k256::ecdsa::Error> for Error {
     fn from(err: k256::ecdsa::Error) -> Self {
         Error::Signature(err.to_string())
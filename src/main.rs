@@ -1,18 +1,37 @@
-use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use axum::{
+    extract::State,
+    http::{header::CONTENT_TYPE, StatusCode},
+    middleware,
+    response::IntoResponse,
+    routing::get,
+    Extension, Json, Router,
+};
 use log::{info, warn};
-use rest::middlewares::AuthorizationToken;
 use serde::{Deserialize, Serialize};
 use tokio::signal;
 
-mod default_registry;
-mod error;
-mod pki;
-mod plugin_uri;
-mod rest;
-mod storage;
-
-use pki::SignatureVerifier;
-use storage::Storage;
+use memflow_registry::{
+    error::ResponseResult,
+    pki::Keyring,
+    rest::{
+        self,
+        metrics::{track_requests, Metrics, STORAGE_USAGE_INTERVAL},
+        middlewares::{ApiToken, ApiTokens, FailedAuthLimiter},
+        signing::{sign_index, IndexSigner},
+    },
+    storage::{
+        backend::{BlobStore, FileSystemStore, S3Config, S3Store},
+        database::{MemoryDatabase, PluginDatabase, SqliteDatabase},
+        journal::Journal,
+        upstream::Upstream,
+        CheckStatus, ReadinessReport, RecoveryMode, Storage, INDEX_CHECK_INTERVAL,
+    },
+    SignatureGenerator, SignatureVerifier, MEMFLOW_DEFAULT_REGISTRY,
+    MEMFLOW_DEFAULT_REGISTRY_VERIFYING_KEY,
+};
 
 #[tokio::main]
 async fn main() {
@@ -22,38 +41,337 @@ async fn main() {
     // initialize logging
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
+    // collect all api tokens
+    let mut tokens = match std::env::var("MEMFLOW_TOKENS_FILE") {
+        Ok(tokens_file) => ApiTokens::from_file(tokens_file).expect("unable to load token file"),
+        Err(_) => ApiTokens::default(),
+    };
     match std::env::var("MEMFLOW_BEARER_TOKEN") {
         Ok(token) if token.is_empty() => {
             warn!("authentication token is empty, THIS IS POTENTIALLY INSECURE.")
         }
-        Err(_) => warn!("no authentication token set, THIS IS POTENTIALLY INSECURE."),
-        _ => (),
+        Ok(token) => tokens
+            .insert(ApiToken::admin("default", &token))
+            .expect("unable to add authentication token"),
+        Err(_) => (),
+    }
+    // reject clients with too many failed authentication attempts
+    let max_failures = std::env::var("MEMFLOW_AUTH_MAX_FAILURES")
+        .map(|v| {
+            v.parse()
+                .expect("MEMFLOW_AUTH_MAX_FAILURES must be a number")
+        })
+        .unwrap_or(10);
+    let lockout = std::env::var("MEMFLOW_AUTH_LOCKOUT")
+        .map(|v| {
+            v.parse()
+                .expect("MEMFLOW_AUTH_LOCKOUT must be a number of seconds")
+        })
+        .unwrap_or(300);
+    tokens = tokens.with_limiter(
+        FailedAuthLimiter::new(max_failures, Duration::from_secs(lockout)).with_trusted_proxies(
+            std::env::var("MEMFLOW_TRUSTED_PROXIES")
+                .map(|v| v.parse().expect("MEMFLOW_TRUSTED_PROXIES must be a number"))
+                .unwrap_or(0),
+        ),
+    );
+
+    if tokens.is_empty() {
+        warn!("no authentication token set, THIS IS POTENTIALLY INSECURE.");
+    } else {
+        info!(
+            "accepting api tokens: {}",
+            tokens.names().collect::<Vec<_>>().join(", ")
+        );
+    }
+
+    let backend: Box<dyn BlobStore> = match std::env::var("MEMFLOW_STORAGE_BACKEND").as_deref() {
+        Ok("s3") => {
+            let config = s3_config_from_env();
+            info!(
+                "storing plugins in s3 bucket `{}` with prefix `{}`",
+                config.bucket,
+                config.prefix.as_deref().unwrap_or_default()
+            );
+            Box::new(S3Store::new(&config).expect("unable to create s3 storage backend"))
+        }
+        Ok("fs") | Err(_) => {
+            let root = std::env::var("MEMFLOW_STORAGE_ROOT").unwrap_or_else(|_| ".storage".into());
+            info!("storing plugins in `{}`", root);
+            Box::new(FileSystemStore::new(&root).expect("unable to create storage backend"))
+        }
+        Ok(backend) => panic!("unknown storage backend `{}`", backend),
+    };
+
+    let database: Box<dyn PluginDatabase> = match std::env::var("MEMFLOW_DATABASE").as_deref() {
+        Ok("sqlite") => {
+            let path = std::env::var("MEMFLOW_DATABASE_PATH")
+                .unwrap_or_else(|_| "memflow-registry.db".into());
+            info!("using plugin database `{}`", path);
+            Box::new(SqliteDatabase::open(&path).expect("unable to open plugin database"))
+        }
+        Ok("memory") | Err(_) => Box::new(MemoryDatabase::new()),
+        Ok(database) => panic!("unknown plugin database `{}`", database),
+    };
+
+    let mut storage = Storage::with_database(backend, database)
+        .await
+        .expect("unable to create storage handler");
+
+    // limit the size of uploaded files which are streamed into a temporary directory
+    if let Ok(max_upload_size) = std::env::var("MEMFLOW_MAX_UPLOAD_SIZE") {
+        storage = storage.with_max_upload_size(
+            max_upload_size
+                .parse()
+                .expect("MEMFLOW_MAX_UPLOAD_SIZE must be a number of bytes"),
+        );
+    }
+    if let Ok(upload_dir) = std::env::var("MEMFLOW_UPLOAD_DIR") {
+        storage = storage.with_upload_dir(upload_dir);
+    }
+    if let Ok(min_free_space) = std::env::var("MEMFLOW_MIN_FREE_DISK_SPACE") {
+        storage = storage.with_min_free_space(
+            min_free_space
+                .parse()
+                .expect("MEMFLOW_MIN_FREE_DISK_SPACE must be a number of bytes"),
+        );
     }
 
-    let root = std::env::var("MEMFLOW_STORAGE_ROOT").unwrap_or_else(|_| ".storage".into());
-    info!("storing plugins in `{}`", root);
-    let mut storage = Storage::new(&root).expect("unable to create storage handler");
+    // keep deleted files in the trash for the given number of seconds
+    let trash_retention = std::env::var("MEMFLOW_TRASH_RETENTION")
+        .ok()
+        .map(|retention| {
+            Duration::from_secs(
+                retention
+                    .parse()
+                    .expect("MEMFLOW_TRASH_RETENTION must be a number of seconds"),
+            )
+        });
+    if let Some(retention) = trash_retention {
+        info!(
+            "keeping deleted plugins in the trash for {}s",
+            retention.as_secs()
+        );
+        storage = storage.with_trash(retention);
+    }
 
-    // use public key file if specified
+    // collect all trusted public keys
+    let mut keyring = Keyring::new();
     if let Ok(public_key_file) = std::env::var("MEMFLOW_PUBLIC_KEY_FILE") {
-        let signature_verifier =
-            SignatureVerifier::new(public_key_file).expect("unable to load public key file");
-        storage = storage.with_signature_verifier(signature_verifier);
+        keyring
+            .add_list(&public_key_file)
+            .expect("unable to load public key file");
+    }
+    if let Ok(public_keys) = std::env::var("MEMFLOW_PUBLIC_KEYS") {
+        keyring
+            .add_list(&public_keys)
+            .expect("unable to load public keys");
+    }
+    if let Ok(public_key_dir) = std::env::var("MEMFLOW_PUBLIC_KEY_DIR") {
+        keyring
+            .add_dir(public_key_dir)
+            .expect("unable to load public key directory");
+    }
+
+    // mirror missing plugins from an upstream registry
+    if std::env::var("MEMFLOW_PULL_THROUGH").is_ok_and(|v| v == "1" || v == "true") {
+        let upstream = upstream_from_env();
+        info!(
+            "mirroring missing plugins from `{}` signed by key `{}`",
+            upstream.registry(),
+            upstream.key_id()
+        );
+        keyring
+            .insert(upstream.key_id(), upstream.verifier().clone())
+            .expect("unable to trust upstream key");
+        storage = storage.with_upstream(upstream);
+    }
+
+    let has_keyring = !keyring.is_empty();
+    if has_keyring {
+        info!(
+            "trusting signatures from keys: {}",
+            keyring.key_ids().collect::<Vec<_>>().join(", ")
+        );
+        storage = storage.with_keyring(keyring);
     } else {
-        warn!("public key file not set, THIS IS POTENTIALLY INSECURE.");
+        warn!("no public keys set, THIS IS POTENTIALLY INSECURE.");
+    }
+
+    // keep the database in sync with other instances sharing the same backend
+    let sync_interval = std::env::var("MEMFLOW_SYNC_INTERVAL").ok().map(|interval| {
+        Duration::from_secs(
+            interval
+                .parse()
+                .expect("MEMFLOW_SYNC_INTERVAL must be a number of seconds"),
+        )
+    });
+    if let Some(interval) = sync_interval {
+        let retention = std::env::var("MEMFLOW_JOURNAL_RETENTION")
+            .map(|v| {
+                v.parse()
+                    .expect("MEMFLOW_JOURNAL_RETENTION must be a number of seconds")
+            })
+            .unwrap_or(3600);
+        let journal = match std::env::var("MEMFLOW_INSTANCE_ID") {
+            Ok(instance_id) => Journal::new(&instance_id, Duration::from_secs(retention))
+                .expect("invalid MEMFLOW_INSTANCE_ID"),
+            Err(_) => Journal::with_random_id(Duration::from_secs(retention)),
+        };
+        info!(
+            "syncing with other instances every {}s as instance `{}`",
+            interval.as_secs(),
+            journal.instance_id()
+        );
+        storage = storage.with_journal(journal);
+    }
+
+    // startup passes and background tasks only run on the fully configured storage,
+    // otherwise their changes would not be journaled or verified
+
+    // rebuild a persistent database from the storage if requested
+    if std::env::var("MEMFLOW_DATABASE_REBUILD").is_ok_and(|v| v == "1" || v == "true") {
+        let files = storage
+            .rebuild_database()
+            .await
+            .expect("unable to rebuild plugin database");
+        info!("rebuilt plugin database from {} metadata files", files);
+    }
+
+    // repair inconsistencies left behind by a crash
+    let recovery_mode = std::env::var("MEMFLOW_RECOVERY")
+        .map(|v| v.parse().expect("invalid MEMFLOW_RECOVERY mode"))
+        .unwrap_or(RecoveryMode::Quick);
+    storage
+        .recover(recovery_mode)
+        .await
+        .expect("unable to recover storage");
+
+    // re-verify all plugins in case the trusted keys changed
+    if has_keyring {
+        storage
+            .verify_on_keyring_change()
+            .await
+            .expect("unable to verify stored plugins");
+    }
+
+    // keep the index check of readiness probes up to date without listing the backend on every probe
+    {
+        let storage = storage.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(INDEX_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                storage.refresh_index_check().await;
+            }
+        });
+    }
+
+    // computing the storage usage lists the entire backend, so it is not done on every scrape
+    let metrics = Metrics::new();
+    {
+        let storage = storage.clone();
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(STORAGE_USAGE_INTERVAL);
+            loop {
+                interval.tick().await;
+                metrics.refresh_storage_usage(&storage).await;
+            }
+        });
+    }
+
+    if trash_retention.is_some() {
+        let storage = storage.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+            loop {
+                interval.tick().await;
+                if let Err(err) = storage.purge_trash().await {
+                    warn!("unable to purge trash: {}", err);
+                }
+            }
+        });
     }
 
+    if let Some(interval) = sync_interval {
+        let storage = storage.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                if let Err(err) = storage.sync_journal().await {
+                    warn!("unable to sync with other instances: {}", err);
+                }
+            }
+        });
+    }
+
+    // sign index responses so clients can detect tampering mirrors
+    let index_signer =
+        std::env::var("MEMFLOW_INDEX_PRIVATE_KEY_FILE")
+            .ok()
+            .map(|private_key_file| {
+                let generator = SignatureGenerator::new(private_key_file)
+                    .expect("unable to load index private key file");
+                info!(
+                    "signing index responses with key {}",
+                    generator.fingerprint()
+                );
+                let mut signer = IndexSigner::new(generator);
+                if let Ok(validity) = std::env::var("MEMFLOW_INDEX_SIGNATURE_VALIDITY") {
+                    signer =
+                        signer.with_validity(Duration::from_secs(validity.parse().expect(
+                            "MEMFLOW_INDEX_SIGNATURE_VALIDITY must be a number of seconds",
+                        )));
+                }
+                signer
+            });
+
     // build our application with a single route
-    let app = app(storage);
+    let app = app(storage, tokens, index_signer, metrics);
 
     // run our app with hyper, listening globally on port 3000
     let addr = std::env::var("MEMFLOW_ADDR").unwrap_or_else(|_| "0.0.0.0:3000".into());
     let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
     info!("serving memflow-registry on `{}`", addr);
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .unwrap();
+}
+
+/// Reads the s3 backend configuration from the `MEMFLOW_S3_*` environment variables.
+fn s3_config_from_env() -> S3Config {
+    S3Config {
+        endpoint: std::env::var("MEMFLOW_S3_ENDPOINT").ok(),
+        bucket: std::env::var("MEMFLOW_S3_BUCKET").expect("MEMFLOW_S3_BUCKET must be set"),
+        prefix: std::env::var("MEMFLOW_S3_PREFIX").ok(),
+        region: std::env::var("MEMFLOW_S3_REGION").ok(),
+        access_key_id: std::env::var("MEMFLOW_S3_ACCESS_KEY_ID").ok(),
+        secret_access_key: std::env::var("MEMFLOW_S3_SECRET_ACCESS_KEY").ok(),
+        allow_http: std::env::var("MEMFLOW_S3_ALLOW_HTTP").is_ok_and(|v| v == "1" || v == "true"),
+    }
+}
+
+/// Reads the pull-through configuration from the `MEMFLOW_UPSTREAM_*` environment variables.
+fn upstream_from_env() -> Upstream {
+    let registry = std::env::var("MEMFLOW_UPSTREAM_REGISTRY")
+        .unwrap_or_else(|_| MEMFLOW_DEFAULT_REGISTRY.to_owned());
+    let verifier = match std::env::var("MEMFLOW_UPSTREAM_PUBLIC_KEY_FILE") {
+        Ok(public_key_file) => SignatureVerifier::new(public_key_file)
+            .expect("unable to load upstream public key file"),
+        Err(_) if registry == MEMFLOW_DEFAULT_REGISTRY => {
+            SignatureVerifier::with_str(MEMFLOW_DEFAULT_REGISTRY_VERIFYING_KEY)
+                .expect("unable to load default registry public key")
+        }
+        Err(_) => panic!("MEMFLOW_UPSTREAM_PUBLIC_KEY_FILE must be set for custom upstreams"),
+    };
+    let key_id = std::env::var("MEMFLOW_UPSTREAM_KEY_ID").unwrap_or_else(|_| "upstream".into());
+    Upstream::new(&registry, &key_id, verifier).expect("unable to create upstream client")
 }
 
 async fn shutdown_signal() {
@@ -80,16 +398,28 @@ async fn shutdown_signal() {
     }
 }
 
-fn app(storage: Storage) -> Router {
-    let auth_token = AuthorizationToken::new(std::env::var("MEMFLOW_BEARER_TOKEN").ok());
-
+fn app(
+    storage: Storage,
+    tokens: ApiTokens,
+    index_signer: Option<IndexSigner>,
+    metrics: Metrics,
+) -> Router {
     let routes = Router::new()
         .route("/health", get(health))
+        .route("/livez", get(livez))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(render_metrics))
+        .layer(Extension(metrics.clone()))
         .with_state(storage.clone());
 
-    Router::new()
-        .merge(routes)
-        .merge(rest::routes::app(storage, auth_token))
+    let mut app =
+        Router::new()
+            .merge(routes)
+            .merge(rest::routes::app(storage, tokens, metrics.clone()));
+    if let Some(index_signer) = index_signer {
+        app = app.route_layer(middleware::from_fn_with_state(index_signer, sign_index));
+    }
+    app.route_layer(middleware::from_fn_with_state(metrics, track_requests))
 }
 
 /// Health status of the service
@@ -103,7 +433,7 @@ pub enum HealthResponse {
 async fn health(
     State(storage): State<Storage>,
 ) -> std::result::Result<Json<HealthResponse>, (axum::http::StatusCode, Json<HealthResponse>)> {
-    match storage.health() {
+    match storage.health().await {
         Ok(()) => Ok(HealthResponse::Ok.into()),
         Err(_) => Err((
             StatusCode::SERVICE_UNAVAILABLE,
@@ -111,3 +441,37 @@ async fn health(
         )),
     }
 }
+
+/// Returns ok as long as the server is able to handle requests
+async fn livez() -> Json<HealthResponse> {
+    HealthResponse::Ok.into()
+}
+
+/// Returns the results of all readiness checks, fails if any of the checks failed
+async fn readyz(State(storage): State<Storage>) -> (StatusCode, Json<ReadinessReport>) {
+    let report = storage.readiness().await;
+    for check in report.checks.iter() {
+        if check.status != CheckStatus::Pass {
+            warn!(
+                "readiness check {} did not pass: {}",
+                check.name, check.message
+            );
+        }
+    }
+
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, report.into())
+}
+
+/// Returns all metrics in the prometheus text format
+async fn render_metrics(
+    State(storage): State<Storage>,
+    Extension(metrics): Extension<Metrics>,
+) -> ResponseResult<impl IntoResponse> {
+    let metrics = metrics.render(&storage)?;
+    Ok(([(CONTENT_TYPE, "text/plain; version=0.0.4")], metrics))
+}
@@ -12,6 +12,8 @@ use crate::{
 /// `coredump` - will just pull latest
 /// `coredump:latest` - will also pull latest
 /// `coredump:0.2.0` - will pull the newest binary with this specific version
+/// `coredump:^0.2` - will pull the newest version matching the semver requirement
+/// `coredump:stable` - will pull the binary the `stable` tag points to
 /// `memflow.registry.io/coredump` - pulls from another registry
 pub struct PluginUri {
     registry: String,
@@ -120,6 +122,18 @@ pub mod tests {
         assert_eq!(path.version(), "0.2.0");
     }
 
+    #[test]
+    pub fn plugin_path_with_version_requirement() {
+        let path: PluginUri = "coredump:^0.2".parse().unwrap();
+        assert_eq!(path.image(), "coredump");
+        assert_eq!(path.version(), "^0.2");
+
+        let path: PluginUri = "registry.memflow.xyz/coredump:>=0.2, <0.3".parse().unwrap();
+        assert_eq!(path.registry(), "https://registry.memflow.xyz");
+        assert_eq!(path.image(), "coredump");
+        assert_eq!(path.version(), ">=0.2, <0.3");
+    }
+
     #[test]
     pub fn plugin_path_with_registry() {
         let path: PluginUri = "registry.memflow.xyz/coredump:0.2.0".parse().unwrap();
@@ -0,0 +1,249 @@
+//! Prometheus metrics of the registry, served at `/metrics`
+
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use log::warn;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+use crate::{
+    error::{ApiError, Error, Result},
+    storage::Storage,
+};
+
+/// Prefix of all metric names
+const NAMESPACE: &str = "memflow_registry";
+
+/// Interval in which the storage usage should be refreshed in the background,
+/// it lists the entire backend and is therefore not computed on every scrape
+pub const STORAGE_USAGE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// All metrics collected by the registry.
+///
+/// Counters are updated while requests are handled, the index gauges are refreshed whenever the metrics are rendered
+/// and the storage gauge is refreshed by [`Metrics::refresh_storage_usage`].
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    uploads_accepted: IntCounter,
+    uploads_rejected: IntCounterVec,
+    downloads: IntCounterVec,
+    served_bytes: IntCounter,
+    index_plugins: IntGauge,
+    index_variants: IntGauge,
+    storage_bytes: IntGauge,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let requests = IntCounterVec::new(
+            opts("http_requests_total", "Number of handled requests"),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time it took to handle a request",
+            )
+            .namespace(NAMESPACE),
+            &["method", "route"],
+        )
+        .unwrap();
+        let uploads_accepted = IntCounter::with_opts(opts(
+            "uploads_accepted_total",
+            "Number of files that have been added",
+        ))
+        .unwrap();
+        let uploads_rejected = IntCounterVec::new(
+            opts("uploads_rejected_total", "Number of rejected uploads"),
+            &["reason"],
+        )
+        .unwrap();
+        let downloads = IntCounterVec::new(
+            opts("downloads_total", "Number of started file downloads"),
+            &["plugin", "digest"],
+        )
+        .unwrap();
+        let served_bytes = IntCounter::with_opts(opts(
+            "served_bytes_total",
+            "Number of bytes sent to clients downloading files",
+        ))
+        .unwrap();
+        let index_plugins =
+            IntGauge::with_opts(opts("index_plugins", "Number of plugins in the database"))
+                .unwrap();
+        let index_variants = IntGauge::with_opts(opts(
+            "index_variants",
+            "Number of plugin variants in the database",
+        ))
+        .unwrap();
+        let storage_bytes = IntGauge::with_opts(opts(
+            "storage_bytes",
+            "Total size of all stored files in bytes",
+        ))
+        .unwrap();
+
+        // registering only fails for duplicate metric names
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry
+            .register(Box::new(request_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(uploads_accepted.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(uploads_rejected.clone()))
+            .unwrap();
+        registry.register(Box::new(downloads.clone())).unwrap();
+        registry.register(Box::new(served_bytes.clone())).unwrap();
+        registry.register(Box::new(index_plugins.clone())).unwrap();
+        registry.register(Box::new(index_variants.clone())).unwrap();
+        registry.register(Box::new(storage_bytes.clone())).unwrap();
+
+        Self {
+            registry,
+            requests,
+            request_duration,
+            uploads_accepted,
+            uploads_rejected,
+            downloads,
+            served_bytes,
+            index_plugins,
+            index_variants,
+            storage_bytes,
+        }
+    }
+
+    /// Counts a file that has been added to the registry.
+    pub fn upload_accepted(&self) {
+        self.uploads_accepted.inc();
+    }
+
+    /// Counts an upload that has been rejected for the given reason.
+    pub fn upload_rejected(&self, reason: &str) {
+        self.uploads_rejected.with_label_values(&[reason]).inc();
+    }
+
+    /// Counts a started download of a file.
+    pub fn download(&self, plugin_name: &str, digest: &str) {
+        self.downloads
+            .with_label_values(&[plugin_name, digest])
+            .inc();
+    }
+
+    /// Returns the counter of bytes sent to clients.
+    pub fn served_bytes(&self) -> IntCounter {
+        self.served_bytes.clone()
+    }
+
+    /// Computes the total size of all stored files.
+    pub async fn refresh_storage_usage(&self, storage: &Storage) {
+        // keep the last known value in case the backend is temporarily unavailable
+        match storage.disk_usage().await {
+            Ok(size) => self.storage_bytes.set(size as i64),
+            Err(err) => warn!("unable to compute storage usage: {}", err),
+        }
+    }
+
+    /// Refreshes the index gauges and returns all metrics in the prometheus text format.
+    pub fn render(&self, storage: &Storage) -> Result<String> {
+        {
+            let database = storage.database();
+            self.index_plugins.set(database.plugins()?.len() as i64);
+            self.index_variants.set(database.count()? as i64);
+        }
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|err| Error::Unknown(err.to_string()))?;
+        String::from_utf8(buffer).map_err(|err| Error::Parse(err.to_string()))
+    }
+}
+
+#[inline]
+fn opts(name: &str, help: &str) -> Opts {
+    Opts::new(name, help).namespace(NAMESPACE)
+}
+
+/// Returns the reason an upload has been rejected with.
+///
+/// Errors can carry a specific reason in their details, the error code is used otherwise.
+pub fn rejection_reason(err: &ApiError) -> String {
+    err.body
+        .details
+        .as_ref()
+        .and_then(|details| details.get("reason"))
+        .and_then(|reason| reason.as_str())
+        .map(str::to_owned)
+        .or_else(|| {
+            serde_json::to_value(err.body.code)
+                .ok()
+                .and_then(|code| code.as_str().map(str::to_owned))
+        })
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+/// Middleware that counts requests and measures their latency per route.
+///
+/// Routes are identified by their path template so the number of label values stays bounded.
+pub async fn track_requests(
+    State(metrics): State<Metrics>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| "unknown".to_owned());
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+
+    metrics
+        .request_duration
+        .with_label_values(&[&method, &route])
+        .observe(start.elapsed().as_secs_f64());
+    metrics
+        .requests
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .inc();
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use super::*;
+
+    #[test]
+    fn rejection_reasons() {
+        let err = ApiError::new(StatusCode::BAD_REQUEST, "invalid signature")
+            .with_details(serde_json::json!({ "reason": "bad_signature" }));
+        assert_eq!(rejection_reason(&err), "bad_signature");
+        let err = ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "file is too large");
+        assert_eq!(rejection_reason(&err), "too_large");
+    }
+}
@@ -1,5 +1,11 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use axum::{
-    extract::{Request, State},
+    extract::{ConnectInfo, Request, State},
     http::StatusCode,
     middleware::Next,
     response::Response,
@@ -9,35 +15,396 @@ use axum_extra::{
     TypedHeader,
 };
 use log::warn;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+
+use crate::error::{ApiError, Error, ResponseResult, Result, ResultExt};
 
-#[derive(Clone)]
-pub struct AuthorizationToken {
-    token: Option<String>,
+/// Permission granted to an api token
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Upload new files
+    Upload,
+    /// Delete existing files
+    Delete,
+    /// Move tags of plugins
+    Tag,
+    /// All permissions including maintenance endpoints, not restricted to specific plugins
+    Admin,
 }
 
-impl AuthorizationToken {
-    pub fn new(token: Option<String>) -> Self {
-        Self { token }
+/// A named api token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    /// Name of the token, used for logging
+    pub name: String,
+    /// Hex encoded sha256 hash of the secret token
+    pub hash: String,
+    /// Permissions granted to this token
+    pub scopes: Vec<Scope>,
+    /// Plugin names this token is allowed to modify, all plugins if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plugins: Option<Vec<String>>,
+}
+
+impl ApiToken {
+    /// Creates a token with the given secret which grants full access.
+    pub fn admin(name: &str, token: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            hash: hash_token(token),
+            scopes: vec![Scope::Admin],
+            plugins: None,
+        }
+    }
+
+    /// Returns an error if the token does not grant the given scope.
+    pub fn require(&self, scope: Scope) -> ResponseResult<()> {
+        if self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope) {
+            Ok(())
+        } else {
+            warn!("token `{}` is missing scope {:?}", self.name, scope);
+            Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                format!("token is missing the {:?} scope", scope).to_lowercase(),
+            )
+            .with_details(serde_json::json!({ "scope": scope })))
+        }
     }
+
+    /// Returns an error if the token is not allowed to modify the given plugin.
+    pub fn require_plugin(&self, plugin_name: &str) -> ResponseResult<()> {
+        match &self.plugins {
+            Some(plugins)
+                if !self.scopes.contains(&Scope::Admin)
+                    && !plugins.iter().any(|p| p == plugin_name) =>
+            {
+                warn!(
+                    "token `{}` is not allowed to modify plugin `{}`",
+                    self.name, plugin_name
+                );
+                Err(ApiError::new(
+                    StatusCode::FORBIDDEN,
+                    format!("token is not allowed to modify plugin `{}`", plugin_name),
+                )
+                .with_details(serde_json::json!({ "plugin": plugin_name })))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Token file format
+#[derive(Deserialize)]
+struct ApiTokensFile {
+    #[serde(default)]
+    tokens: Vec<ApiToken>,
+}
+
+/// All api tokens accepted by the registry.
+///
+/// In case no tokens are configured the authed routes are accessible without a token.
+#[derive(Clone, Default)]
+pub struct ApiTokens {
+    tokens: Arc<Vec<ApiToken>>,
+    limiter: Option<Arc<FailedAuthLimiter>>,
 }
 
+impl ApiTokens {
+    pub fn new(mut tokens: Vec<ApiToken>) -> Result<Self> {
+        for token in tokens.iter_mut() {
+            token.hash.make_ascii_lowercase();
+        }
+        for (i, token) in tokens.iter().enumerate() {
+            if token.hash.len() != 64 || !token.hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(Error::Parse(format!(
+                    "hash of token `{}` is not a sha256 hex digest",
+                    token.name
+                )));
+            }
+            if tokens[..i].iter().any(|t| t.name == token.name) {
+                return Err(Error::AlreadyExists(format!(
+                    "token `{}` is defined twice",
+                    token.name
+                )));
+            }
+        }
+
+        Ok(Self {
+            tokens: Arc::new(tokens),
+            limiter: None,
+        })
+    }
+
+    /// Rejects clients with too many failed authentication attempts.
+    pub fn with_limiter(mut self, limiter: FailedAuthLimiter) -> Self {
+        self.limiter = Some(Arc::new(limiter));
+        self
+    }
+
+    /// Loads all tokens from a toml file:
+    /// ```toml
+    /// [[tokens]]
+    /// name = "coredump-ci"
+    /// hash = "<sha256 of the token>"
+    /// scopes = ["upload", "delete"]
+    /// plugins = ["coredump"]
+    /// ```
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = std::fs::read_to_string(path.as_ref())
+            .context(&format!("Unable to read token file {:?}", path.as_ref()))?;
+        Self::from_toml(&content)
+    }
+
+    /// Parses all tokens from the contents of a token file.
+    pub fn from_toml(content: &str) -> Result<Self> {
+        let file: ApiTokensFile =
+            toml::from_str(content).map_err(|err| Error::Parse(err.message().to_owned()))?;
+        Self::new(file.tokens)
+    }
+
+    /// Adds a token, fails if a token with the same name already exists.
+    pub fn insert(&mut self, token: ApiToken) -> Result<()> {
+        let mut tokens = self.tokens.as_ref().clone();
+        tokens.push(token);
+        self.tokens = Self::new(tokens)?.tokens;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// Returns the names of all tokens.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.tokens.iter().map(|token| token.name.as_str())
+    }
+
+    /// Returns the token matching the given secret.
+    ///
+    /// All tokens are compared in constant time so the timing does not reveal
+    /// how much of a hash matched or which token matched.
+    pub fn find(&self, token: &str) -> Option<&ApiToken> {
+        let hash = hash_token(token);
+        let mut found = None;
+        for t in self.tokens.iter() {
+            if bool::from(t.hash.as_bytes().ct_eq(hash.as_bytes())) {
+                found = Some(t);
+            }
+        }
+        found
+    }
+}
+
+/// Tracks failed authentication attempts per client ip.
+///
+/// Clients exceeding the maximum number of failures within the window
+/// are rejected with `429 Too Many Requests` until the window expires.
+pub struct FailedAuthLimiter {
+    max_failures: u32,
+    window: Duration,
+    trusted_proxies: usize,
+    failures: Mutex<HashMap<IpAddr, (u32, Instant)>>,
+}
+
+impl FailedAuthLimiter {
+    pub fn new(max_failures: u32, window: Duration) -> Self {
+        Self {
+            max_failures,
+            window,
+            trusted_proxies: 0,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sets the number of reverse proxies in front of the registry.
+    ///
+    /// Every proxy appends the address of its peer to the `X-Forwarded-For` header,
+    /// so the client ip is the entry appended by the outermost trusted proxy.
+    /// Entries further left are controlled by the client and are never used.
+    /// The header is ignored if no proxies are trusted.
+    pub fn with_trusted_proxies(mut self, trusted_proxies: usize) -> Self {
+        self.trusted_proxies = trusted_proxies;
+        self
+    }
+
+    /// Returns the ip of the client that sent the request.
+    fn client_ip(&self, request: &Request) -> Option<IpAddr> {
+        if self.trusted_proxies > 0 {
+            let entries = request
+                .headers()
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .collect::<Vec<_>>();
+            let forwarded_for = entries
+                .iter()
+                .rev()
+                .nth(self.trusted_proxies - 1)
+                .and_then(|ip| ip.trim().parse().ok());
+            if forwarded_for.is_some() {
+                return forwarded_for;
+            }
+        }
+
+        request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|info| info.0.ip())
+    }
+
+    /// Returns true if the client exceeded the maximum number of failures.
+    fn is_limited(&self, ip: IpAddr) -> bool {
+        match self.failures.lock().get(&ip) {
+            Some((count, since)) => *count >= self.max_failures && since.elapsed() < self.window,
+            None => false,
+        }
+    }
+
+    fn record_failure(&self, ip: IpAddr) {
+        let mut failures = self.failures.lock();
+        failures.retain(|_, (_, since)| since.elapsed() < self.window);
+        let entry = failures.entry(ip).or_insert((0, Instant::now()));
+        entry.0 += 1;
+    }
+
+    fn reset(&self, ip: IpAddr) {
+        self.failures.lock().remove(&ip);
+    }
+}
+
+/// Returns the hex encoded sha256 hash of the token as stored in the token file.
+pub fn hash_token(token: &str) -> String {
+    sha256::digest(token)
+}
+
+/// Authenticates the request and adds the matching [`ApiToken`] to the request extensions.
 pub async fn check_token(
-    State(auth_token): State<AuthorizationToken>,
-    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
-    request: Request,
+    State(tokens): State<ApiTokens>,
+    authorization: Option<TypedHeader<Authorization<Bearer>>>,
+    mut request: Request,
     next: Next,
-) -> std::result::Result<Response, StatusCode> {
-    if let Some(token) = auth_token.token {
-        if authorization.0.token() != token {
-            // token is set but it does not match
-            warn!(
-                "invalid token when uploading plugin: token={}",
-                authorization.0.token()
-            );
-            return Err(StatusCode::UNAUTHORIZED);
+) -> ResponseResult<Response> {
+    let client_ip = tokens
+        .limiter
+        .as_ref()
+        .and_then(|limiter| Some((limiter, limiter.client_ip(&request)?)));
+    if let Some((limiter, ip)) = client_ip {
+        if limiter.is_limited(ip) {
+            warn!("too many failed authentication attempts from {}", ip);
+            return Err(ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "too many failed authentication attempts",
+            ));
         }
     }
 
+    let token = if tokens.is_empty() {
+        // authentication is disabled
+        ApiToken {
+            name: "anonymous".to_owned(),
+            hash: String::new(),
+            scopes: vec![Scope::Admin],
+            plugins: None,
+        }
+    } else {
+        match authorization.and_then(|TypedHeader(auth)| tokens.find(auth.token()).cloned()) {
+            Some(token) => {
+                if let Some((limiter, ip)) = client_ip {
+                    limiter.reset(ip);
+                }
+                token
+            }
+            None => {
+                // never log the presented token
+                warn!(
+                    "invalid or missing token when accessing {} from {}",
+                    request.uri().path(),
+                    client_ip.map(|(_, ip)| ip.to_string()).unwrap_or_default()
+                );
+                if let Some((limiter, ip)) = client_ip {
+                    limiter.record_failure(ip);
+                }
+                return Err(ApiError::new(
+                    StatusCode::UNAUTHORIZED,
+                    "invalid or missing token",
+                ));
+            }
+        }
+    };
+
+    request.extensions_mut().insert(token);
     let response = next.run(request).await;
     Ok(response)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens() {
+        let tokens = ApiTokens::from_toml(&format!(
+            r#"
+[[tokens]]
+name = "coredump-ci"
+hash = "{}"
+scopes = ["upload"]
+plugins = ["coredump"]
+
+[[tokens]]
+name = "admin"
+hash = "{}"
+scopes = ["admin"]
+"#,
+            hash_token("ci-secret"),
+            hash_token("admin-secret")
+        ))
+        .unwrap();
+        assert_eq!(tokens.names().collect::<Vec<_>>(), ["coredump-ci", "admin"]);
+        assert!(tokens.find("invalid").is_none());
+
+        let ci = tokens.find("ci-secret").unwrap();
+        assert!(ci.require(Scope::Upload).is_ok());
+        assert!(ci.require(Scope::Delete).is_err());
+        assert!(ci.require_plugin("coredump").is_ok());
+        assert!(ci.require_plugin("qemu").is_err());
+
+        let admin = tokens.find("admin-secret").unwrap();
+        assert!(admin.require(Scope::Delete).is_ok());
+        assert!(admin.require_plugin("qemu").is_ok());
+
+        // plaintext tokens and duplicate names are rejected
+        assert!(ApiTokens::from_toml(
+            "[[tokens]]\nname = \"ci\"\nhash = \"secret\"\nscopes = [\"upload\"]"
+        )
+        .is_err());
+        let mut tokens = tokens;
+        assert!(tokens.insert(ApiToken::admin("admin", "secret")).is_err());
+    }
+
+    #[test]
+    fn test_limiter() {
+        let limiter = FailedAuthLimiter::new(2, Duration::from_secs(60));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+
+        limiter.record_failure(ip);
+        assert!(!limiter.is_limited(ip));
+        limiter.record_failure(ip);
+        assert!(limiter.is_limited(ip));
+        assert!(!limiter.is_limited(other));
+
+        limiter.reset(ip);
+        assert!(!limiter.is_limited(ip));
+
+        // failures expire after the window
+        let limiter = FailedAuthLimiter::new(1, Duration::ZERO);
+        limiter.record_failure(ip);
+        assert!(!limiter.is_limited(ip));
+    }
+}
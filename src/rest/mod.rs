@@ -1,3 +1,5 @@
+pub mod metrics;
 pub mod middlewares;
 pub mod models;
 pub mod routes;
+pub mod signing;
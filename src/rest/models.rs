@@ -1,27 +1,148 @@
+use axum::http::StatusCode;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-use crate::storage::database::PluginVariant;
+use crate::storage::{database::PluginVariant, TagEntry};
 
-#[derive(Clone, Serialize, Deserialize)]
+/// Name and description of a plugin
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
 pub struct PluginInfo {
     pub name: String,
     pub description: String,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+/// List of all plugins
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
 pub struct PluginsAllResponse {
     pub plugins: Vec<PluginInfo>,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+/// Plugin variants matching the search parameters
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
 pub struct PluginsFindResponse {
     pub plugins: Vec<PluginVariant>,
     pub skip: usize,
 }
 
+/// Multipart form of an upload request
+#[derive(ToSchema)]
+pub struct PluginUploadForm {
+    /// The plugin binary
+    #[schema(value_type = String, format = Binary)]
+    pub file: Vec<u8>,
+    /// Hex encoded signature of the plugin binary
+    pub signature: String,
+}
+
 /// Result of an upload request
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub enum PluginUploadResponse {
     Added,
     AlreadyExists,
 }
+
+/// Result of a database rebuild request
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct DatabaseRebuildResponse {
+    /// Number of metadata files that have been added to the database
+    pub files: usize,
+}
+
+/// Tags of a plugin and the files they currently point to
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct PluginTagsResponse {
+    pub tags: Vec<TagEntry>,
+}
+
+/// All movements of a single tag, the oldest entry comes first
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct PluginTagHistoryResponse {
+    pub history: Vec<TagEntry>,
+}
+
+/// Request to move a tag to another file
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct PluginTagMoveRequest {
+    pub digest: String,
+}
+
+/// Machine-readable kind of an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    AlreadyExists,
+    TooLarge,
+    RateLimited,
+    Internal,
+}
+
+impl ErrorCode {
+    /// Returns the error code matching the http status code.
+    pub fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED => Self::Unauthorized,
+            StatusCode::FORBIDDEN => Self::Forbidden,
+            StatusCode::NOT_FOUND => Self::NotFound,
+            StatusCode::CONFLICT => Self::AlreadyExists,
+            StatusCode::PAYLOAD_TOO_LARGE => Self::TooLarge,
+            StatusCode::TOO_MANY_REQUESTS => Self::RateLimited,
+            status if status.is_client_error() => Self::BadRequest,
+            _ => Self::Internal,
+        }
+    }
+}
+
+/// Body of all error responses
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    /// Human-readable description of the error
+    pub message: String,
+    /// Additional information depending on the error
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+/// Schema of the plugin descriptor defined in memflow
+#[derive(ToSchema)]
+#[schema(as = PluginDescriptorInfo)]
+pub struct PluginDescriptorInfoSchema {
+    pub plugin_kind: PluginKindSchema,
+    pub export_name: String,
+    pub file_type: PluginFileTypeSchema,
+    pub architecture: PluginArchitectureSchema,
+    /// The memflow abi version
+    pub plugin_version: i32,
+    pub name: String,
+    pub version: String,
+    pub description: String,
+}
+
+#[derive(ToSchema)]
+#[schema(as = PluginKind, rename_all = "snake_case")]
+pub enum PluginKindSchema {
+    Connector,
+    Os,
+}
+
+#[derive(ToSchema)]
+#[schema(as = PluginFileType, rename_all = "snake_case")]
+pub enum PluginFileTypeSchema {
+    Pe,
+    Elf,
+    Mach,
+}
+
+/// Architecture of a plugin, unknown architectures are serialized as `{"unknown": id}`
+#[derive(ToSchema)]
+#[schema(as = PluginArchitecture, rename_all = "snake_case")]
+pub enum PluginArchitectureSchema {
+    X86,
+    X86_64,
+    Arm,
+    Arm64,
+}
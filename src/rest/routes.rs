@@ -7,52 +7,139 @@ use axum::{
     },
     middleware,
     response::IntoResponse,
-    routing::{delete, get, post},
-    Json, Router,
+    routing::{delete, get, post, put},
+    Extension, Json, Router,
 };
 use bytes::BytesMut;
+use futures_util::StreamExt;
 use log::info;
 use memflow::plugins::plugin_analyzer;
-use tokio_util::io::ReaderStream;
+use utoipa::{
+    openapi::security::{Http, HttpAuthScheme, SecurityScheme},
+    Modify, OpenApi,
+};
 
 use crate::{
-    error::ResponseResult,
-    storage::{database::PluginDatabaseFindParams, PluginMetadata, Storage, UploadResponse},
+    error::{ApiError, Error, ResponseResult},
+    storage::{
+        database::PluginDatabaseFindParams, PluginMetadata, Storage, UploadResponse, VerifyReport,
+    },
 };
 
 use super::{
-    middlewares::{check_token, AuthorizationToken},
-    models::{PluginUploadResponse, PluginsAllResponse, PluginsFindResponse},
+    metrics::{rejection_reason, Metrics},
+    middlewares::{check_token, ApiToken, ApiTokens, Scope},
+    models::{
+        DatabaseRebuildResponse, ErrorResponse, PluginTagHistoryResponse, PluginTagMoveRequest,
+        PluginTagsResponse, PluginUploadForm, PluginUploadResponse, PluginsAllResponse,
+        PluginsFindResponse,
+    },
 };
 
-pub fn app(storage: Storage, auth_token: AuthorizationToken) -> Router {
+/// OpenAPI document of the rest api, served at `/openapi.json`
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        get_plugins,
+        find_plugin_variants,
+        get_plugin_tags,
+        get_plugin_tag_history,
+        move_tag,
+        upload_file,
+        download_file_by_digest,
+        get_file_metadata_by_digest,
+        delete_file_by_digest,
+        restore_file_by_digest,
+        yank_file_by_digest,
+        unyank_file_by_digest,
+        verify_files,
+        rebuild_database,
+    ),
+    modifiers(&BearerSecurity)
+)]
+pub struct ApiDoc;
+
+/// Adds the bearer token authentication used by the authed routes
+struct BearerSecurity;
+
+impl Modify for BearerSecurity {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme(
+                "bearer",
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+            );
+    }
+}
+
+pub fn app(storage: Storage, tokens: ApiTokens, metrics: Metrics) -> Router {
+    // the file size is limited while streaming the upload, leave some room for the remaining fields
+    let body_limit = storage.max_upload_size() as usize + 64 * 1024;
+
     let authed_routes = Router::new()
         .route("/files", post(upload_file))
         .route("/files/{digest}", delete(delete_file_by_digest))
-        .layer(DefaultBodyLimit::max(20 * 1024 * 1024)) // 20 mb
-        .route_layer(middleware::from_fn_with_state(
-            auth_token.clone(),
-            check_token,
-        ))
+        .route("/files/{digest}/restore", post(restore_file_by_digest))
+        .route("/files/{digest}/yank", post(yank_file_by_digest))
+        .route("/files/{digest}/unyank", post(unyank_file_by_digest))
+        .route("/plugins/{plugin_name}/tags/{tag}", put(move_tag))
+        .route("/files/verify", post(verify_files))
+        .route("/database/rebuild", post(rebuild_database))
+        .layer(DefaultBodyLimit::max(body_limit))
+        .route_layer(middleware::from_fn_with_state(tokens, check_token))
         .with_state(storage.clone());
 
     let public_routes = Router::new()
+        .route("/openapi.json", get(openapi))
         .route("/plugins", get(get_plugins))
         .route("/plugins/{plugin_name}", get(find_plugin_variants))
+        .route("/plugins/{plugin_name}/tags", get(get_plugin_tags))
+        .route(
+            "/plugins/{plugin_name}/tags/{tag}",
+            get(get_plugin_tag_history),
+        )
         .route("/files/{digest}", get(download_file_by_digest))
         .route("/files/{digest}/metadata", get(get_file_metadata_by_digest))
         .with_state(storage);
 
-    Router::new().merge(public_routes).merge(authed_routes)
+    Router::new()
+        .merge(public_routes)
+        .merge(authed_routes)
+        .layer(Extension(metrics))
+}
+
+/// Returns the OpenAPI document of the rest api
+async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    ApiDoc::openapi().into()
 }
 
 /// Returns a list of all available plugins
+#[utoipa::path(
+    get,
+    path = "/plugins",
+    tag = "plugins",
+    responses(
+        (status = OK, body = PluginsAllResponse),
+    )
+)]
 async fn get_plugins(State(storage): State<Storage>) -> ResponseResult<Json<PluginsAllResponse>> {
-    let plugins = storage.database().plugins();
+    let plugins = storage.database().plugins()?;
     Ok(PluginsAllResponse { plugins }.into())
 }
 
 /// Returns a list of plugins based on the given filter parameters
+#[utoipa::path(
+    get,
+    path = "/plugins/{plugin_name}",
+    tag = "plugins",
+    params(("plugin_name" = String, Path), PluginDatabaseFindParams),
+    responses(
+        (status = OK, body = PluginsFindResponse),
+        (status = "4XX", description = "Invalid request", body = ErrorResponse),
+    )
+)]
 async fn find_plugin_variants(
     State(storage): State<Storage>,
     params: Query<PluginDatabaseFindParams>,
@@ -61,8 +148,8 @@ async fn find_plugin_variants(
     // find entries in database
     let params: PluginDatabaseFindParams = params.0;
     let entries = storage
-        .database()
-        .plugin_variants(&plugin_name, params.clone());
+        .plugin_variants(&plugin_name, params.clone())
+        .await?;
 
     Ok(PluginsFindResponse {
         plugins: entries,
@@ -71,100 +158,225 @@ async fn find_plugin_variants(
     .into())
 }
 
+/// Returns all tags of a plugin and the files they currently point to
+#[utoipa::path(
+    get,
+    path = "/plugins/{plugin_name}/tags",
+    tag = "tags",
+    params(("plugin_name" = String, Path)),
+    responses(
+        (status = OK, body = PluginTagsResponse),
+    )
+)]
+async fn get_plugin_tags(
+    State(storage): State<Storage>,
+    Path(plugin_name): Path<String>,
+) -> ResponseResult<Json<PluginTagsResponse>> {
+    let tags = storage.tags(&plugin_name).await?;
+    Ok(PluginTagsResponse {
+        tags: tags.current(),
+    }
+    .into())
+}
+
+/// Returns all movements of a single tag
+#[utoipa::path(
+    get,
+    path = "/plugins/{plugin_name}/tags/{tag}",
+    tag = "tags",
+    params(("plugin_name" = String, Path), ("tag" = String, Path)),
+    responses(
+        (status = OK, body = PluginTagHistoryResponse),
+        (status = "4XX", description = "Invalid request", body = ErrorResponse),
+    )
+)]
+async fn get_plugin_tag_history(
+    State(storage): State<Storage>,
+    Path((plugin_name, tag)): Path<(String, String)>,
+) -> ResponseResult<Json<PluginTagHistoryResponse>> {
+    let tags = storage.tags(&plugin_name).await?;
+    let history = tags
+        .history
+        .into_iter()
+        .filter(|entry| entry.tag == tag)
+        .collect::<Vec<_>>();
+    if history.is_empty() {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "tag not found"));
+    }
+    Ok(PluginTagHistoryResponse { history }.into())
+}
+
+/// Moves a tag of a plugin to the file with the given digest
+#[utoipa::path(
+    put,
+    path = "/plugins/{plugin_name}/tags/{tag}",
+    tag = "tags",
+    params(("plugin_name" = String, Path), ("tag" = String, Path)),
+    request_body = PluginTagMoveRequest,
+    responses(
+        (status = OK, body = PluginTagsResponse),
+        (status = "4XX", description = "Invalid request", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+async fn move_tag(
+    State(storage): State<Storage>,
+    Extension(token): Extension<ApiToken>,
+    Path((plugin_name, tag)): Path<(String, String)>,
+    Json(request): Json<PluginTagMoveRequest>,
+) -> ResponseResult<Json<PluginTagsResponse>> {
+    token.require(Scope::Tag)?;
+    token.require_plugin(&plugin_name)?;
+
+    let tags = storage
+        .move_tag(&plugin_name, &tag, &request.digest, &token.name)
+        .await?;
+    Ok(PluginTagsResponse { tags }.into())
+}
+
 /// Posts a file to the backend and analyzes it.
+#[utoipa::path(
+    post,
+    path = "/files",
+    tag = "files",
+    request_body(content = PluginUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = OK, body = PluginUploadResponse),
+        (status = "4XX", description = "Invalid request", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
 async fn upload_file(
     State(storage): State<Storage>,
-    mut multipart: Multipart,
+    Extension(token): Extension<ApiToken>,
+    Extension(metrics): Extension<Metrics>,
+    multipart: Multipart,
 ) -> ResponseResult<Json<PluginUploadResponse>> {
+    let result = receive_upload(&storage, &token, multipart).await;
+    match &result {
+        Ok(PluginUploadResponse::Added) => metrics.upload_accepted(),
+        Ok(PluginUploadResponse::AlreadyExists) => metrics.upload_rejected("duplicate"),
+        Err(err) => metrics.upload_rejected(&rejection_reason(err)),
+    }
+    result.map(Json)
+}
+
+/// Reads the multipart form of an upload and adds the file to the storage.
+async fn receive_upload(
+    storage: &Storage,
+    token: &ApiToken,
+    mut multipart: Multipart,
+) -> ResponseResult<PluginUploadResponse> {
+    token.require(Scope::Upload)?;
+
     let mut file_data = None;
     let mut file_signature = None;
 
-    while let Some(mut field) = multipart
-        .next_field()
-        .await
-        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?
-    {
+    while let Some(mut field) = multipart.next_field().await? {
         if let Some(name) = field.name() {
             match name {
                 "signature" => {
-                    file_signature = Some(
-                        field
-                            .text()
-                            .await
-                            .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?,
-                    );
+                    file_signature = Some(field.text().await?);
                 }
                 "file" => {
-                    // read the buffer
-                    let mut data = BytesMut::new();
-                    let mut checked = false;
-                    while let Some(chunk) = field
-                        .chunk()
-                        .await
-                        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?
-                    {
-                        data.extend_from_slice(&chunk);
+                    // stream the file into a temporary file
+                    let mut file = storage.spool()?;
+                    let mut head = BytesMut::new();
+                    while let Some(chunk) = field.chunk().await? {
+                        file.write(&chunk).await?;
 
                         // check if this file is a potential binary or early abort
-                        if !checked && data.len() > 4 {
-                            plugin_analyzer::is_binary(&data[..])
-                                .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
-                            checked = true;
+                        if head.len() <= 4 {
+                            head.extend_from_slice(&chunk);
+                            if head.len() > 4 {
+                                plugin_analyzer::is_binary(&head[..]).map_err(Error::from)?;
+                            }
                         }
                     }
-                    file_data = Some(data.freeze());
+                    file_data = Some(file);
                 }
                 _ => {
-                    return Err((
+                    return Err(ApiError::new(
                         StatusCode::BAD_REQUEST,
-                        "unexpected field in multipart form".to_owned(),
+                        "unexpected field in multipart form",
                     ))
                 }
             }
         }
     }
 
-    if let Some(data) = file_data {
+    if let Some(file) = file_data {
         if let Some(signature) = file_signature {
             info!(
                 "trying to add file to registry: size={} signature={}",
-                data.len(),
+                file.size(),
                 &signature
             );
 
+            // ensure the token is allowed to modify all plugins contained in the file
+            if token.plugins.is_some() {
+                let map = file.map()?;
+                let descriptors =
+                    plugin_analyzer::parse_descriptors(&map[..]).map_err(Error::from)?;
+                for descriptor in descriptors.iter() {
+                    token.require_plugin(&descriptor.name)?;
+                }
+            }
+
             // TODO: do not require duplicate struct definitions here
             // upload file
-            let result = storage.upload(&data[..], &signature).await;
+            let result = storage.upload_spooled(file, &signature).await;
             match result {
-                Ok(UploadResponse::Added) => Ok(PluginUploadResponse::Added.into()),
-                Ok(UploadResponse::AlreadyExists) => Ok(PluginUploadResponse::AlreadyExists.into()),
-                Err(err) => Err((StatusCode::BAD_REQUEST, err.to_string())),
+                Ok(UploadResponse::Added) => Ok(PluginUploadResponse::Added),
+                Ok(UploadResponse::AlreadyExists) => Ok(PluginUploadResponse::AlreadyExists),
+                Err(err) => Err(err.into()),
             }
         } else {
-            Err((
+            Err(ApiError::new(
                 StatusCode::BAD_REQUEST,
-                "file signature is required".to_owned(),
+                "file signature is required",
             ))
         }
     } else {
-        Err((StatusCode::BAD_REQUEST, "file is required".to_owned()))
+        Err(ApiError::new(StatusCode::BAD_REQUEST, "file is required"))
     }
 }
 
 /// Retrieves a file by it's digest.
+#[utoipa::path(
+    get,
+    path = "/files/{digest}",
+    tag = "files",
+    params(("digest" = String, Path)),
+    responses(
+        (status = OK, description = "The plugin binary", content_type = "application/octet-stream", body = [u8]),
+        (status = "4XX", description = "Invalid request", body = ErrorResponse),
+    )
+)]
 async fn download_file_by_digest(
     State(storage): State<Storage>,
+    Extension(metrics): Extension<Metrics>,
     Path(digest): Path<String>,
 ) -> ResponseResult<impl IntoResponse> {
     // try to download the file by its digest
-    let file = storage
-        .download(&digest)
-        .await
-        .map_err(|_| (StatusCode::NOT_FOUND, "plugin not found".to_owned()))?;
-
-    // convert into a stream
-    let file_len = file.metadata().await.unwrap().len();
-    let stream = ReaderStream::new(file);
+    let (info, stream) = storage.download(&digest).await?;
+
+    let plugin_name = storage
+        .database()
+        .find_by_digest(&digest)
+        .ok()
+        .flatten()
+        .map(|variant| variant.descriptor.name)
+        .unwrap_or_else(|| "unknown".to_owned());
+    metrics.download(&plugin_name, &digest);
+
+    // convert into a stream, only bytes which have actually been sent are counted
+    let served_bytes = metrics.served_bytes();
+    let stream = stream.inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            served_bytes.inc_by(chunk.len() as u64);
+        }
+    });
     let body = Body::from_stream(stream);
     let mut response = body.into_response();
 
@@ -175,53 +387,596 @@ async fn download_file_by_digest(
     );
     headers.append(
         CONTENT_LENGTH,
-        HeaderValue::from_str(&format!("{}", file_len)).unwrap(),
+        HeaderValue::from_str(&format!("{}", info.size)).unwrap(),
     );
 
     Ok(response)
 }
 
 /// Retrieves a file's metadata by it's digest.
+#[utoipa::path(
+    get,
+    path = "/files/{digest}/metadata",
+    tag = "files",
+    params(("digest" = String, Path)),
+    responses(
+        (status = OK, body = PluginMetadata),
+        (status = "4XX", description = "Invalid request", body = ErrorResponse),
+    )
+)]
 async fn get_file_metadata_by_digest(
     State(storage): State<Storage>,
     Path(digest): Path<String>,
 ) -> ResponseResult<Json<PluginMetadata>> {
     // try to download the file by its digest
-    let metadata = storage
-        .metadata(&digest)
-        .await
-        .map_err(|_| (StatusCode::NOT_FOUND, "plugin not found".to_owned()))?;
+    let metadata = storage.metadata(&digest).await?;
     Ok(metadata.into())
 }
 
 /// Deletes the file with the given digest.
+#[utoipa::path(
+    delete,
+    path = "/files/{digest}",
+    tag = "files",
+    params(("digest" = String, Path)),
+    responses(
+        (status = OK),
+        (status = "4XX", description = "Invalid request", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
 async fn delete_file_by_digest(
     State(storage): State<Storage>,
+    Extension(token): Extension<ApiToken>,
     Path(digest): Path<String>,
 ) -> ResponseResult<()> {
+    token.require(Scope::Delete)?;
+    require_plugins_of(&storage, &token, &digest).await?;
+
     // try to delete the file by its digest
-    storage
-        .delete(&digest)
-        .await
-        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    storage.delete(&digest).await?;
+
+    Ok(())
+}
+
+/// Restores the file with the given digest from the trash.
+#[utoipa::path(
+    post,
+    path = "/files/{digest}/restore",
+    tag = "files",
+    params(("digest" = String, Path)),
+    responses(
+        (status = OK),
+        (status = "4XX", description = "Invalid request", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+async fn restore_file_by_digest(
+    State(storage): State<Storage>,
+    Extension(token): Extension<ApiToken>,
+    Path(digest): Path<String>,
+) -> ResponseResult<()> {
+    token.require(Scope::Delete)?;
+    require_plugins_of(&storage, &token, &digest).await?;
+
+    storage.restore(&digest).await?;
+
+    Ok(())
+}
+
+/// Hides the file with the given digest from plugin queries without deleting it.
+#[utoipa::path(
+    post,
+    path = "/files/{digest}/yank",
+    tag = "files",
+    params(("digest" = String, Path)),
+    responses(
+        (status = OK),
+        (status = "4XX", description = "Invalid request", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+async fn yank_file_by_digest(
+    State(storage): State<Storage>,
+    Extension(token): Extension<ApiToken>,
+    Path(digest): Path<String>,
+) -> ResponseResult<()> {
+    set_yanked(&storage, &token, &digest, true).await
+}
+
+/// Makes a yanked file visible in plugin queries again.
+#[utoipa::path(
+    post,
+    path = "/files/{digest}/unyank",
+    tag = "files",
+    params(("digest" = String, Path)),
+    responses(
+        (status = OK),
+        (status = "4XX", description = "Invalid request", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+async fn unyank_file_by_digest(
+    State(storage): State<Storage>,
+    Extension(token): Extension<ApiToken>,
+    Path(digest): Path<String>,
+) -> ResponseResult<()> {
+    set_yanked(&storage, &token, &digest, false).await
+}
 
+async fn set_yanked(
+    storage: &Storage,
+    token: &ApiToken,
+    digest: &str,
+    yanked: bool,
+) -> ResponseResult<()> {
+    token.require(Scope::Delete)?;
+    require_plugins_of(storage, token, digest).await?;
+
+    storage.set_yanked(digest, yanked).await?;
+    Ok(())
+}
+
+/// Ensures the token is allowed to modify all plugins contained in the file with the given digest.
+async fn require_plugins_of(
+    storage: &Storage,
+    token: &ApiToken,
+    digest: &str,
+) -> ResponseResult<()> {
+    if token.plugins.is_some() {
+        let metadata = storage.metadata(digest).await?;
+        for descriptor in metadata.descriptors.iter() {
+            token.require_plugin(&descriptor.name)?;
+        }
+    }
     Ok(())
 }
 
+/// Re-verifies the signatures of all files and quarantines files which are not trusted anymore.
+#[utoipa::path(
+    post,
+    path = "/files/verify",
+    tag = "maintenance",
+    responses(
+        (status = OK, body = VerifyReport),
+        (status = "4XX", description = "Invalid request", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+async fn verify_files(
+    State(storage): State<Storage>,
+    Extension(token): Extension<ApiToken>,
+) -> ResponseResult<Json<VerifyReport>> {
+    token.require(Scope::Admin)?;
+    let report = storage.verify_all().await?;
+    Ok(report.into())
+}
+
+/// Rebuilds the plugin database from all metadata files in the storage.
+#[utoipa::path(
+    post,
+    path = "/database/rebuild",
+    tag = "maintenance",
+    responses(
+        (status = OK, body = DatabaseRebuildResponse),
+        (status = "4XX", description = "Invalid request", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+async fn rebuild_database(
+    State(storage): State<Storage>,
+    Extension(token): Extension<ApiToken>,
+) -> ResponseResult<Json<DatabaseRebuildResponse>> {
+    token.require(Scope::Admin)?;
+    let files = storage.rebuild_database().await?;
+    info!("rebuilt plugin database from {} metadata files", files);
+    Ok(DatabaseRebuildResponse { files }.into())
+}
+
 #[cfg(test)]
 mod test {
     use axum::http::Request;
     use tower::util::ServiceExt;
 
+    use crate::storage::backend::MemoryStore;
+
     use super::*;
 
+    const BOUNDARY: &str = "memflow-registry-test";
+
+    fn multipart_body(fields: &[(&str, &[u8])]) -> Body {
+        let mut body = Vec::new();
+        for (name, value) in fields {
+            body.extend_from_slice(
+                format!("--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n")
+                    .as_bytes(),
+            );
+            body.extend_from_slice(value);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{BOUNDARY}--\r\n").as_bytes());
+        Body::from(body)
+    }
+
     #[tokio::test]
     async fn push() {
-        // create temporary directory
-        let root = tempfile::tempdir().unwrap();
-        let storage = Storage::new(root.into_path()).expect("unable to create storage handler");
-        //let app = app(storage);
+        let storage = Storage::with_backend(MemoryStore::new())
+            .await
+            .expect("unable to create storage handler");
+        let mut tokens = ApiTokens::default();
+        tokens.insert(ApiToken::admin("default", "token")).unwrap();
+        let metrics = Metrics::default();
+        let app = app(storage.clone(), tokens, metrics.clone());
+
+        // uploading without a valid token is rejected
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/files")
+                    .header("Authorization", "Bearer invalid")
+                    .header(
+                        CONTENT_TYPE,
+                        format!("multipart/form-data; boundary={BOUNDARY}"),
+                    )
+                    .body(multipart_body(&[("signature", b"00")]))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // uploading a file which is not a binary is rejected
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/files")
+                    .header("Authorization", "Bearer token")
+                    .header(
+                        CONTENT_TYPE,
+                        format!("multipart/form-data; boundary={BOUNDARY}"),
+                    )
+                    .body(multipart_body(&[
+                        ("file", b"not a plugin binary"),
+                        ("signature", b"00"),
+                    ]))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // files exceeding the size limit are rejected
+        let limited = Storage::with_backend(MemoryStore::new())
+            .await
+            .expect("unable to create storage handler")
+            .with_max_upload_size(8);
+        let mut tokens = ApiTokens::default();
+        tokens.insert(ApiToken::admin("default", "token")).unwrap();
+        let response = super::app(limited, tokens, metrics.clone())
+            .oneshot(
+                Request::post("/files")
+                    .header("Authorization", "Bearer token")
+                    .header(
+                        CONTENT_TYPE,
+                        format!("multipart/form-data; boundary={BOUNDARY}"),
+                    )
+                    .body(multipart_body(&[
+                        ("file", b"not a plugin binary"),
+                        ("signature", b"00"),
+                    ]))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // nothing was added
+        let response = app
+            .oneshot(Request::get("/files/abcdef").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // rejected uploads are counted by their reason
+        metrics.refresh_storage_usage(&storage).await;
+        let metrics = metrics.render(&storage).unwrap();
+        assert!(metrics.contains("memflow_registry_uploads_rejected_total{reason=\"analyzer\"} 1"));
+        assert!(metrics.contains("memflow_registry_uploads_rejected_total{reason=\"too_large\"} 1"));
+        assert!(metrics.contains("memflow_registry_uploads_accepted_total 0"));
+        assert!(metrics.contains("memflow_registry_index_variants 0"));
+        assert!(metrics.contains("memflow_registry_storage_bytes 0"));
+    }
+
+    #[tokio::test]
+    async fn scopes() {
+        let storage = Storage::with_backend(MemoryStore::new())
+            .await
+            .expect("unable to create storage handler");
+        let mut tokens = ApiTokens::default();
+        tokens
+            .insert(ApiToken {
+                name: "coredump-ci".to_owned(),
+                hash: crate::rest::middlewares::hash_token("ci"),
+                scopes: vec![Scope::Upload],
+                plugins: Some(vec!["coredump".to_owned()]),
+            })
+            .unwrap();
+        let app = app(storage, tokens, Metrics::default());
+
+        // missing scopes are rejected before touching the storage
+        for request in [
+            Request::delete("/files/abcdef"),
+            Request::post("/files/verify"),
+            Request::post("/database/rebuild"),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    request
+                        .header("Authorization", "Bearer ci")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+        let response = app
+            .clone()
+            .oneshot(
+                Request::put("/plugins/coredump/tags/stable")
+                    .header("Authorization", "Bearer ci")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(r#"{"digest":"abcdef"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // requests without a token are rejected
+        let response = app
+            .oneshot(
+                Request::post("/database/rebuild")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn failed_auth_limit() {
+        use std::{net::SocketAddr, time::Duration};
+
+        use axum::extract::ConnectInfo;
+
+        use crate::rest::middlewares::FailedAuthLimiter;
+
+        let storage = Storage::with_backend(MemoryStore::new())
+            .await
+            .expect("unable to create storage handler");
+        let mut tokens =
+            ApiTokens::default().with_limiter(FailedAuthLimiter::new(2, Duration::from_secs(60)));
+        tokens.insert(ApiToken::admin("default", "token")).unwrap();
+        let app = app(storage, tokens, Metrics::default());
+
+        let request = |token: &str, addr: &str| {
+            Request::post("/database/rebuild")
+                .header("Authorization", format!("Bearer {token}"))
+                .extension(ConnectInfo(addr.parse::<SocketAddr>().unwrap()))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        for _ in 0..2 {
+            let response = app
+                .clone()
+                .oneshot(request("invalid", "10.0.0.1:1234"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        // the client is rejected even with a valid token
+        let response = app
+            .clone()
+            .oneshot(request("token", "10.0.0.1:1234"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // other clients are unaffected
+        let response = app
+            .clone()
+            .oneshot(request("token", "10.0.0.2:1234"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // clients behind the trusted proxy can not evade the limit by spoofing addresses
+        let storage = Storage::with_backend(MemoryStore::new())
+            .await
+            .expect("unable to create storage handler");
+        let mut tokens = ApiTokens::default().with_limiter(
+            FailedAuthLimiter::new(2, Duration::from_secs(60)).with_trusted_proxies(1),
+        );
+        tokens.insert(ApiToken::admin("default", "token")).unwrap();
+        let proxied = crate::rest::routes::app(storage, tokens, Metrics::default());
+        let forwarded = |token: &str, forwarded_for: &str| {
+            let mut request = request(token, "10.0.0.100:1234");
+            request
+                .headers_mut()
+                .insert("x-forwarded-for", forwarded_for.parse().unwrap());
+            request
+        };
+        for spoofed in ["1.1.1.1", "2.2.2.2", "3.3.3.3"] {
+            let response = proxied
+                .clone()
+                .oneshot(forwarded("invalid", &format!("{}, 10.0.0.3", spoofed)))
+                .await
+                .unwrap();
+            assert_ne!(response.status(), StatusCode::OK);
+        }
+        let response = proxied
+            .clone()
+            .oneshot(forwarded("token", "4.4.4.4, 10.0.0.3"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // the proxy itself is not limited for other clients
+        let response = proxied
+            .oneshot(forwarded("token", "10.0.0.4"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
 
-        // run tests
+    #[tokio::test]
+    async fn yank() {
+        use crate::storage::database::tests::put_plugin;
+
+        let backend = MemoryStore::new();
+        let digest = put_plugin(&backend, "coredump", "0.2.0").await;
+        let storage = Storage::with_backend(backend).await.unwrap();
+        let app = app(storage, ApiTokens::default(), Metrics::default());
+
+        let request = |method: &str, uri: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let find = |app: Router, uri: String| async move {
+            let response = app.oneshot(request("GET", &uri)).await.unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<PluginsFindResponse>(&body)
+                .unwrap()
+                .plugins
+                .len()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request("POST", &format!("/files/{}/yank", digest)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(find(app.clone(), "/plugins/coredump".to_owned()).await, 0);
+        assert_eq!(
+            find(app.clone(), "/plugins/coredump?version=0.2.0".to_owned()).await,
+            0
+        );
+
+        // yanked files can still be resolved and downloaded by their digest
+        assert_eq!(
+            find(app.clone(), format!("/plugins/coredump?version={}", digest)).await,
+            1
+        );
+        let response = app
+            .clone()
+            .oneshot(request("GET", &format!("/files/{}", digest)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .clone()
+            .oneshot(request("POST", &format!("/files/{}/unyank", digest)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(find(app.clone(), "/plugins/coredump".to_owned()).await, 1);
+
+        let response = app
+            .oneshot(request("POST", "/files/bbbb/yank"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn openapi_and_errors() {
+        let storage = Storage::with_backend(MemoryStore::new())
+            .await
+            .expect("unable to create storage handler");
+        let mut tokens = ApiTokens::default();
+        tokens
+            .insert(ApiToken {
+                name: "ci".to_owned(),
+                hash: crate::rest::middlewares::hash_token("ci"),
+                scopes: vec![Scope::Upload, Scope::Delete],
+                plugins: None,
+            })
+            .unwrap();
+        let app = app(storage, tokens, Metrics::default());
+
+        let body = |response: axum::response::Response| async move {
+            axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(Request::get("/openapi.json").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let document: serde_json::Value = serde_json::from_slice(&body(response).await).unwrap();
+        assert!(document["paths"]["/plugins/{plugin_name}"]["get"].is_object());
+        assert!(document["components"]["schemas"]["ErrorResponse"].is_object());
+        assert!(document["components"]["securitySchemes"]["bearer"].is_object());
+
+        // errors are returned as structured json
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/database/rebuild")
+                    .header("Authorization", "Bearer ci")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let error: ErrorResponse = serde_json::from_slice(&body(response).await).unwrap();
+        assert_eq!(error.code, crate::rest::models::ErrorCode::Forbidden);
+        assert_eq!(error.details, Some(serde_json::json!({ "scope": "admin" })));
+        assert!(matches!(Error::from(error), Error::Forbidden(_)));
+
+        let response = app
+            .clone()
+            .oneshot(Request::get("/files/abcdef").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let error: ErrorResponse = serde_json::from_slice(&body(response).await).unwrap();
+        assert!(matches!(Error::from(error), Error::NotFound(_)));
+
+        // storage errors keep their kind instead of becoming internal errors
+        for request in [
+            Request::delete("/files/abcdef"),
+            Request::post("/files/abcdef/yank"),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    request
+                        .header("Authorization", "Bearer ci")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            let error: ErrorResponse = serde_json::from_slice(&body(response).await).unwrap();
+            assert!(matches!(Error::from(error), Error::NotFound(_)));
+        }
     }
 }
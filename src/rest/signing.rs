@@ -0,0 +1,224 @@
+//! Signatures of index responses
+//!
+//! Plugin files are signed by their authors, but the index which tells clients which file is the latest
+//! is not covered by those signatures. A compromised mirror could therefore serve an outdated
+//! but validly signed file as the latest one. To prevent this the registry signs all index responses
+//! with its own key. Each signature covers the request path, the response body and an expiry timestamp,
+//! so responses can neither be served for other queries nor be replayed once they expired.
+
+use std::time::Duration;
+
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Request, State},
+    http::{HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use log::error;
+
+use crate::{
+    error::{ApiError, Error, Result},
+    SignatureGenerator, SignatureVerifier,
+};
+
+/// Header containing the hex encoded signature of an index response
+pub const SIGNATURE_HEADER: &str = "x-memflow-index-signature";
+/// Header containing the unix timestamp after which an index response must not be trusted anymore
+pub const EXPIRES_HEADER: &str = "x-memflow-index-expires";
+
+/// Default time for which signed index responses are valid
+pub const DEFAULT_INDEX_VALIDITY: Duration = Duration::from_secs(60 * 60); // 1 hour
+
+/// Routes whose responses to `GET` requests are signed
+const INDEX_ROUTES: &[&str] = &[
+    "/plugins",
+    "/plugins/{plugin_name}",
+    "/plugins/{plugin_name}/tags",
+    "/plugins/{plugin_name}/tags/{tag}",
+    "/files/{digest}/metadata",
+];
+
+/// Maximum size of a response body that is buffered for signing
+const MAX_INDEX_SIZE: usize = 16 * 1024 * 1024; // 16 mb
+
+/// Signs index responses with the key of the registry
+#[derive(Clone)]
+pub struct IndexSigner {
+    generator: SignatureGenerator,
+    validity: Duration,
+}
+
+impl IndexSigner {
+    pub fn new(generator: SignatureGenerator) -> Self {
+        Self {
+            generator,
+            validity: DEFAULT_INDEX_VALIDITY,
+        }
+    }
+
+    /// Sets the time for which signed responses are valid.
+    pub fn with_validity(mut self, validity: Duration) -> Self {
+        self.validity = validity;
+        self
+    }
+
+    /// Signs the response body of a request to the given path and returns the signature and its expiry.
+    pub fn sign(&self, path_and_query: &str, body: &[u8]) -> Result<(String, i64)> {
+        let expires_at = Utc::now().timestamp() + self.validity.as_secs() as i64;
+        let signature =
+            self.generator
+                .clone()
+                .sign(&signed_payload(path_and_query, expires_at, body))?;
+        Ok((signature, expires_at))
+    }
+}
+
+/// Returns the data covered by the signature of an index response.
+fn signed_payload(path_and_query: &str, expires_at: i64, body: &[u8]) -> Vec<u8> {
+    format!(
+        "memflow-registry-index\n{}\n{}\n{}",
+        path_and_query,
+        expires_at,
+        sha256::digest(body)
+    )
+    .into_bytes()
+}
+
+/// Ensures the index response to a request to the given path has been signed by the registry and has not expired yet.
+pub fn verify_index(
+    verifier: &SignatureVerifier,
+    path_and_query: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<()> {
+    verify_index_at(
+        verifier,
+        path_and_query,
+        headers,
+        body,
+        Utc::now().timestamp(),
+    )
+}
+
+fn verify_index_at(
+    verifier: &SignatureVerifier,
+    path_and_query: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+    now: i64,
+) -> Result<()> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| Error::Signature("index response is not signed".to_owned()))
+    };
+    let signature = header(SIGNATURE_HEADER)?;
+    let expires_at = header(EXPIRES_HEADER)?
+        .parse::<i64>()
+        .map_err(|_| Error::Signature("invalid expiry of index response".to_owned()))?;
+
+    verifier
+        .is_valid(&signed_payload(path_and_query, expires_at, body), signature)
+        .map_err(|_| Error::Signature("signature of index response is invalid".to_owned()))?;
+    if expires_at < now {
+        return Err(Error::Signature(format!(
+            "index response expired {}s ago",
+            now - expires_at
+        )));
+    }
+    Ok(())
+}
+
+/// Middleware that signs successful responses of the index routes.
+pub async fn sign_index(
+    State(signer): State<IndexSigner>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let is_index = request.method() == Method::GET
+        && request
+            .extensions()
+            .get::<MatchedPath>()
+            .is_some_and(|path| INDEX_ROUTES.contains(&path.as_str()));
+    let path_and_query = request
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_default();
+
+    let response = next.run(request).await;
+    if !is_index || !response.status().is_success() {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let signed = match axum::body::to_bytes(body, MAX_INDEX_SIZE).await {
+        Ok(body) => signer
+            .sign(&path_and_query, &body)
+            .map(|(signature, expires_at)| (body, signature, expires_at)),
+        Err(err) => Err(Error::IO(err.to_string())),
+    };
+    let (body, signature, expires_at) = match signed {
+        Ok(signed) => signed,
+        Err(err) => {
+            error!("unable to sign index response: {}", err);
+            return ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "unable to sign index response",
+            )
+            .into_response();
+        }
+    };
+
+    // hex signatures and numbers are always valid header values
+    parts
+        .headers
+        .insert(SIGNATURE_HEADER, HeaderValue::from_str(&signature).unwrap());
+    parts
+        .headers
+        .insert(EXPIRES_HEADER, HeaderValue::from(expires_at));
+    Response::from_parts(parts, Body::from(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pki::tests::key_pair;
+
+    use super::*;
+
+    #[test]
+    fn signed_responses() {
+        let (alice, alice_pem) = key_pair(1);
+        let (_, bob_pem) = key_pair(2);
+        let alice_verifier = SignatureVerifier::with_str(&alice_pem).unwrap();
+        let bob_verifier = SignatureVerifier::with_str(&bob_pem).unwrap();
+
+        let signer = IndexSigner::new(alice).with_validity(Duration::from_secs(60));
+        let path = "/plugins/coredump?limit=1";
+        let body = br#"{"plugins":[],"skip":0}"#;
+        let (signature, expires_at) = signer.sign(path, body).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(SIGNATURE_HEADER, HeaderValue::from_str(&signature).unwrap());
+        headers.insert(EXPIRES_HEADER, HeaderValue::from(expires_at));
+
+        assert!(verify_index(&alice_verifier, path, &headers, body).is_ok());
+        assert!(verify_index(&bob_verifier, path, &headers, body).is_err());
+
+        // responses can not be served for other queries or with a modified body
+        assert!(verify_index(&alice_verifier, "/plugins/coredump", &headers, body).is_err());
+        assert!(verify_index(&alice_verifier, path, &headers, b"{}").is_err());
+
+        // the expiry is covered by the signature
+        let mut extended = headers.clone();
+        extended.insert(EXPIRES_HEADER, HeaderValue::from(expires_at + 3600));
+        assert!(verify_index(&alice_verifier, path, &extended, body).is_err());
+
+        assert!(verify_index_at(&alice_verifier, path, &headers, body, expires_at).is_ok());
+        assert!(verify_index_at(&alice_verifier, path, &headers, body, expires_at + 1).is_err());
+
+        assert!(verify_index(&alice_verifier, path, &HeaderMap::new(), body).is_err());
+    }
+}
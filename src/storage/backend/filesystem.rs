@@ -0,0 +1,372 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::StreamExt;
+use log::info;
+use tempfile::NamedTempFile;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+use crate::error::{Error, Result, ResultExt};
+
+use super::{validate_key, BlobInfo, BlobStore, BlobStream};
+
+/// Temporary files which are older than this are considered leftovers of a crash
+const STALE_TEMP_FILE_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Lock files which are older than this have been left behind by a crashed writer
+const STALE_LOCK_AGE: Duration = Duration::from_secs(30);
+
+/// Stores all blobs as files in a single local directory.
+///
+/// Blobs are written into a hidden temporary file first which is renamed to its final name
+/// once it has been synced to disk, so a crash never leaves a partially written blob behind.
+pub struct FileSystemStore {
+    root: PathBuf,
+}
+
+impl FileSystemStore {
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
+        // TODO: create path if not exists
+        std::fs::read_dir(&root).context(&format!(
+            "Unable to read database directory '{:?}'",
+            root.as_ref()
+        ))?;
+
+        let store = Self {
+            root: root.as_ref().to_path_buf(),
+        };
+        store.remove_stale_temp_files()?;
+        Ok(store)
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        validate_key(key)?;
+        Ok(self.root.join(key))
+    }
+
+    /// Creates a hidden temporary file in the storage root which can be renamed atomically.
+    fn temp_file(&self) -> Result<NamedTempFile> {
+        Ok(tempfile::Builder::new()
+            .prefix(".")
+            .suffix(".tmp")
+            .tempfile_in(&self.root)?)
+    }
+
+    /// Syncs the temporary file to disk and moves it to the final path of the key.
+    async fn persist(&self, temp: NamedTempFile, key: &str) -> Result<()> {
+        let path = self.path(key)?;
+        File::from_std(temp.reopen()?).sync_all().await?;
+        temp.persist(path).map_err(|err| Error::from(err.error))?;
+        self.sync_root().await
+    }
+
+    /// Syncs the storage directory so renames within it survive a crash.
+    async fn sync_root(&self) -> Result<()> {
+        // directories can not be opened for syncing on windows
+        if cfg!(unix) {
+            File::open(&self.root).await?.sync_all().await?;
+        }
+        Ok(())
+    }
+
+    /// Creates the hidden lock file of the key, other instances sharing the directory use the same file.
+    /// The lock is released once the returned guard is dropped.
+    async fn lock(&self, key: &str) -> Result<LockFile> {
+        validate_key(key)?;
+        let path = self.root.join(format!(".{}.lock", key));
+        let locked = || Error::AlreadyExists(format!("blob `{}` is locked by another writer", key));
+        for _ in 0..2 {
+            match tokio::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .await
+            {
+                Ok(_) => return Ok(LockFile { path }),
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                    let age = tokio::fs::metadata(&path)
+                        .await
+                        .and_then(|metadata| metadata.modified())
+                        .ok()
+                        .and_then(|modified| modified.elapsed().ok());
+                    if !age.is_some_and(|age| age > STALE_LOCK_AGE) {
+                        return Err(locked());
+                    }
+                    info!("removing stale lock file {:?}", path);
+                    tokio::fs::remove_file(&path).await.ok();
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Err(locked())
+    }
+
+    /// Removes temporary files of writes that have been interrupted by a crash.
+    fn remove_stale_temp_files(&self) -> Result<()> {
+        for entry in std::fs::read_dir(&self.root)?.filter_map(|entry| entry.ok()) {
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            if !name.starts_with('.') || !name.ends_with(".tmp") {
+                continue;
+            }
+
+            // other instances might still be writing to recent files
+            let age = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok());
+            if age.is_some_and(|age| age > STALE_TEMP_FILE_AGE) {
+                info!("removing stale temporary file {:?}", entry.path());
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlobStore for FileSystemStore {
+    async fn put(&self, key: &str, bytes: Bytes) -> Result<()> {
+        validate_key(key)?;
+        let temp = self.temp_file()?;
+        let mut file = File::from_std(temp.reopen()?);
+        file.write_all(&bytes).await?;
+        file.flush().await?;
+        self.persist(temp, key).await
+    }
+
+    async fn put_file(&self, key: &str, path: &Path) -> Result<()> {
+        validate_key(key)?;
+        let temp = self.temp_file()?;
+        tokio::fs::copy(path, temp.path()).await?;
+        self.persist(temp, key).await
+    }
+
+    async fn get(&self, key: &str) -> Result<BlobStream> {
+        let file = File::open(self.path(key)?)
+            .await
+            .map_err(|err| not_found_or_io(key, err))?;
+        Ok(ReaderStream::new(file)
+            .map(|chunk| chunk.map_err(Error::from))
+            .boxed())
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<BlobInfo>> {
+        match tokio::fs::metadata(self.path(key)?).await {
+            Ok(metadata) => Ok(Some(BlobInfo {
+                size: metadata.len(),
+                modified: metadata.modified().ok(),
+            })),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        tokio::fs::remove_file(self.path(key)?)
+            .await
+            .map_err(|err| not_found_or_io(key, err))
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        // both paths are in the same directory, so the rename is atomic
+        tokio::fs::rename(self.path(from)?, self.path(to)?)
+            .await
+            .map_err(|err| not_found_or_io(from, err))?;
+        self.sync_root().await
+    }
+
+    async fn read_versioned(&self, key: &str) -> Result<Option<(Bytes, String)>> {
+        match tokio::fs::read(self.path(key)?).await {
+            Ok(bytes) => {
+                let version = sha256::digest(&bytes[..]);
+                Ok(Some((bytes.into(), version)))
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn put_if_version(&self, key: &str, bytes: Bytes, version: Option<&str>) -> Result<()> {
+        let _lock = self.lock(key).await?;
+        let current = self.read_versioned(key).await?.map(|(_, version)| version);
+        if current.as_deref() != version {
+            return Err(Error::AlreadyExists(format!(
+                "blob `{}` has been modified concurrently",
+                key
+            )));
+        }
+        self.put(key, bytes).await
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_file() {
+                // skip hidden temporary files
+                if let Some(key) = entry.file_name().to_str().filter(|k| !k.starts_with('.')) {
+                    keys.push(key.to_owned());
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    async fn health(&self) -> Result<()> {
+        let root = self.root.clone();
+        tokio::task::spawn_blocking(move || {
+            let paths = std::fs::read_dir(root)?;
+            for _path in paths.filter_map(|p| p.ok()) {
+                // no-op
+            }
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn available_space(&self) -> Result<Option<u64>> {
+        let root = self.root.clone();
+        tokio::task::spawn_blocking(move || available_space(&root)).await?
+    }
+}
+
+/// Lock file of a blob which is removed once dropped
+struct LockFile {
+    path: PathBuf,
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+#[inline]
+fn not_found_or_io(key: &str, err: std::io::Error) -> Error {
+    if err.kind() == std::io::ErrorKind::NotFound {
+        Error::NotFound(format!("blob `{}` was not found", key))
+    } else {
+        err.into()
+    }
+}
+
+/// Returns the space available to unprivileged users on the file system containing the path.
+#[cfg(unix)]
+pub(crate) fn available_space(path: &Path) -> Result<Option<u64>> {
+    let stat = rustix::fs::statvfs(path).map_err(std::io::Error::from)?;
+    Ok(Some(stat.f_bavail.saturating_mul(stat.f_frsize)))
+}
+
+/// Returns the space available on the file system containing the path.
+#[cfg(not(unix))]
+pub(crate) fn available_space(_path: &Path) -> Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn put_get_delete() {
+        let root = tempfile::tempdir().unwrap();
+        let store = FileSystemStore::new(root.path()).unwrap();
+
+        store
+            .put("abcdef.plugin", Bytes::from_static(b"plugin"))
+            .await
+            .unwrap();
+        assert_eq!(
+            store
+                .stat("abcdef.plugin")
+                .await
+                .unwrap()
+                .map(|info| info.size),
+            Some(6)
+        );
+        assert_eq!(&store.read("abcdef.plugin").await.unwrap()[..], b"plugin");
+        assert_eq!(store.list().await.unwrap(), vec!["abcdef.plugin"]);
+
+        // blobs are replaced atomically without leaving temporary files behind
+        store
+            .put("abcdef.plugin", Bytes::from_static(b"replaced"))
+            .await
+            .unwrap();
+        assert_eq!(&store.read("abcdef.plugin").await.unwrap()[..], b"replaced");
+        assert_eq!(std::fs::read_dir(root.path()).unwrap().count(), 1);
+
+        store
+            .rename("abcdef.plugin", "abcdef.plugin.corrupt")
+            .await
+            .unwrap();
+        assert_eq!(store.list().await.unwrap(), vec!["abcdef.plugin.corrupt"]);
+        store
+            .rename("abcdef.plugin.corrupt", "abcdef.plugin")
+            .await
+            .unwrap();
+
+        store.delete("abcdef.plugin").await.unwrap();
+        assert_eq!(store.stat("abcdef.plugin").await.unwrap(), None);
+        assert!(matches!(
+            store.delete("abcdef.plugin").await,
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn conditional_put() {
+        let root = tempfile::tempdir().unwrap();
+        let store = FileSystemStore::new(root.path()).unwrap();
+
+        store
+            .put_if_version("plugin.tags", Bytes::from_static(b"a"), None)
+            .await
+            .unwrap();
+        let (_, version) = store.read_versioned("plugin.tags").await.unwrap().unwrap();
+        assert!(matches!(
+            store
+                .put_if_version("plugin.tags", Bytes::from_static(b"b"), None)
+                .await,
+            Err(Error::AlreadyExists(_))
+        ));
+        store
+            .put_if_version("plugin.tags", Bytes::from_static(b"b"), Some(&version))
+            .await
+            .unwrap();
+
+        // the blob has been modified since it was read
+        assert!(matches!(
+            store
+                .put_if_version("plugin.tags", Bytes::from_static(b"c"), Some(&version))
+                .await,
+            Err(Error::AlreadyExists(_))
+        ));
+        assert_eq!(&store.read("plugin.tags").await.unwrap()[..], b"b");
+
+        // writes are rejected while another writer holds the lock
+        let lock = root.path().join(".plugin.tags.lock");
+        std::fs::write(&lock, b"").unwrap();
+        let (_, version) = store.read_versioned("plugin.tags").await.unwrap().unwrap();
+        assert!(matches!(
+            store
+                .put_if_version("plugin.tags", Bytes::from_static(b"c"), Some(&version))
+                .await,
+            Err(Error::AlreadyExists(_))
+        ));
+        std::fs::remove_file(&lock).unwrap();
+        store
+            .put_if_version("plugin.tags", Bytes::from_static(b"c"), Some(&version))
+            .await
+            .unwrap();
+        assert!(!lock.exists());
+        assert_eq!(store.list().await.unwrap(), vec!["plugin.tags"]);
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::{stream, StreamExt};
+use parking_lot::RwLock;
+
+use crate::error::{Error, Result};
+
+use super::{validate_key, BlobInfo, BlobStore, BlobStream};
+
+/// Keeps all blobs in memory. Mostly useful for testing.
+#[derive(Default)]
+pub struct MemoryStore {
+    blobs: RwLock<HashMap<String, Blob>>,
+}
+
+struct Blob {
+    bytes: Bytes,
+    modified: SystemTime,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl BlobStore for MemoryStore {
+    async fn put(&self, key: &str, bytes: Bytes) -> Result<()> {
+        validate_key(key)?;
+        let blob = Blob {
+            bytes,
+            modified: SystemTime::now(),
+        };
+        self.blobs.write().insert(key.to_owned(), blob);
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<BlobStream> {
+        let bytes = self
+            .blobs
+            .read()
+            .get(key)
+            .map(|blob| blob.bytes.clone())
+            .ok_or_else(|| Error::NotFound(format!("blob `{}` was not found", key)))?;
+        Ok(stream::iter([Ok(bytes)]).boxed())
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<BlobInfo>> {
+        Ok(self.blobs.read().get(key).map(|blob| BlobInfo {
+            size: blob.bytes.len() as u64,
+            modified: Some(blob.modified),
+        }))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.blobs
+            .write()
+            .remove(key)
+            .map(|_| ())
+            .ok_or_else(|| Error::NotFound(format!("blob `{}` was not found", key)))
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        validate_key(to)?;
+        let mut blobs = self.blobs.write();
+        let blob = blobs
+            .remove(from)
+            .ok_or_else(|| Error::NotFound(format!("blob `{}` was not found", from)))?;
+        blobs.insert(to.to_owned(), blob);
+        Ok(())
+    }
+
+    async fn read_versioned(&self, key: &str) -> Result<Option<(Bytes, String)>> {
+        Ok(self
+            .blobs
+            .read()
+            .get(key)
+            .map(|blob| (blob.bytes.clone(), sha256::digest(&blob.bytes[..]))))
+    }
+
+    async fn put_if_version(&self, key: &str, bytes: Bytes, version: Option<&str>) -> Result<()> {
+        validate_key(key)?;
+        let mut blobs = self.blobs.write();
+        let current = blobs.get(key).map(|blob| sha256::digest(&blob.bytes[..]));
+        if current.as_deref() != version {
+            return Err(Error::AlreadyExists(format!(
+                "blob `{}` has been modified concurrently",
+                key
+            )));
+        }
+        let blob = Blob {
+            bytes,
+            modified: SystemTime::now(),
+        };
+        blobs.insert(key.to_owned(), blob);
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        Ok(self.blobs.read().keys().cloned().collect())
+    }
+
+    async fn health(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn usage(&self) -> Result<u64> {
+        Ok(self
+            .blobs
+            .read()
+            .values()
+            .map(|blob| blob.bytes.len() as u64)
+            .sum())
+    }
+}
@@ -0,0 +1,239 @@
+//! Blob storage backends
+//!
+//! A backend stores opaque blobs under flat keys. The [`Storage`](super::Storage) uses
+//! `{digest}.plugin` for plugin binaries and `{digest}.meta` for their metadata.
+
+use std::path::Path;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use futures_util::{stream::BoxStream, StreamExt};
+
+use crate::error::{Error, Result};
+
+pub mod filesystem;
+pub mod memory;
+pub mod s3;
+
+pub use filesystem::FileSystemStore;
+pub use memory::MemoryStore;
+pub use s3::{S3Config, S3Store};
+
+/// Stream of chunks of a stored blob
+pub type BlobStream = BoxStream<'static, Result<Bytes>>;
+
+/// Information about a stored blob
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobInfo {
+    /// Size of the blob in bytes
+    pub size: u64,
+    /// Time of the last modification if known by the backend
+    pub modified: Option<SystemTime>,
+}
+
+/// Storage backend for plugin binaries and metadata files.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Writes the blob with the given key, an existing blob with the same key is replaced.
+    async fn put(&self, key: &str, bytes: Bytes) -> Result<()>;
+
+    /// Writes the contents of a local file as the blob with the given key.
+    async fn put_file(&self, key: &str, path: &Path) -> Result<()> {
+        self.put(key, tokio::fs::read(path).await?.into()).await
+    }
+
+    /// Returns a stream of the contents of the blob.
+    async fn get(&self, key: &str) -> Result<BlobStream>;
+
+    /// Returns information about the blob or `None` if it does not exist.
+    async fn stat(&self, key: &str) -> Result<Option<BlobInfo>>;
+
+    /// Removes the blob with the given key.
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    /// Moves the blob to a new key without passing its contents through the registry,
+    /// an existing blob with the new key is replaced.
+    async fn rename(&self, from: &str, to: &str) -> Result<()>;
+
+    /// Reads the entire blob together with its current version or returns `None` if it does not exist.
+    async fn read_versioned(&self, key: &str) -> Result<Option<(Bytes, String)>>;
+
+    /// Writes the blob only if its current version still matches the version it has been read with,
+    /// `None` requires that the blob does not exist yet.
+    ///
+    /// Returns [`Error::AlreadyExists`] if the blob has been modified in the meantime.
+    async fn put_if_version(&self, key: &str, bytes: Bytes, version: Option<&str>) -> Result<()>;
+
+    /// Returns the keys of all stored blobs.
+    async fn list(&self) -> Result<Vec<String>>;
+
+    /// Returns the keys which sort after the given key, in lexicographic order.
+    async fn list_after(&self, offset: &str) -> Result<Vec<String>> {
+        let mut keys = self.list().await?;
+        keys.retain(|key| key.as_str() > offset);
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Checks if the backend is still accessible.
+    async fn health(&self) -> Result<()>;
+
+    /// Returns the free space available for new blobs in bytes or `None` if the backend is not limited by a local disk.
+    async fn available_space(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Returns the total size of all stored blobs in bytes.
+    async fn usage(&self) -> Result<u64> {
+        let mut size = 0;
+        for key in self.list().await? {
+            if let Some(info) = self.stat(&key).await? {
+                size += info.size;
+            }
+        }
+        Ok(size)
+    }
+
+    /// Reads the entire blob into memory.
+    async fn read(&self, key: &str) -> Result<Bytes> {
+        let mut stream = self.get(key).await?;
+        let mut data = BytesMut::new();
+        while let Some(chunk) = stream.next().await {
+            data.extend_from_slice(&chunk?);
+        }
+        Ok(data.freeze())
+    }
+}
+
+#[async_trait]
+impl<T: BlobStore + ?Sized> BlobStore for Box<T> {
+    async fn put(&self, key: &str, bytes: Bytes) -> Result<()> {
+        (**self).put(key, bytes).await
+    }
+
+    async fn put_file(&self, key: &str, path: &Path) -> Result<()> {
+        (**self).put_file(key, path).await
+    }
+
+    async fn get(&self, key: &str) -> Result<BlobStream> {
+        (**self).get(key).await
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<BlobInfo>> {
+        (**self).stat(key).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        (**self).delete(key).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        (**self).rename(from, to).await
+    }
+
+    async fn read_versioned(&self, key: &str) -> Result<Option<(Bytes, String)>> {
+        (**self).read_versioned(key).await
+    }
+
+    async fn put_if_version(&self, key: &str, bytes: Bytes, version: Option<&str>) -> Result<()> {
+        (**self).put_if_version(key, bytes, version).await
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        (**self).list().await
+    }
+
+    async fn list_after(&self, offset: &str) -> Result<Vec<String>> {
+        (**self).list_after(offset).await
+    }
+
+    async fn health(&self) -> Result<()> {
+        (**self).health().await
+    }
+
+    async fn available_space(&self) -> Result<Option<u64>> {
+        (**self).available_space().await
+    }
+
+    async fn usage(&self) -> Result<u64> {
+        (**self).usage().await
+    }
+}
+
+#[async_trait]
+impl<T: BlobStore + ?Sized> BlobStore for std::sync::Arc<T> {
+    async fn put(&self, key: &str, bytes: Bytes) -> Result<()> {
+        (**self).put(key, bytes).await
+    }
+
+    async fn put_file(&self, key: &str, path: &Path) -> Result<()> {
+        (**self).put_file(key, path).await
+    }
+
+    async fn get(&self, key: &str) -> Result<BlobStream> {
+        (**self).get(key).await
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<BlobInfo>> {
+        (**self).stat(key).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        (**self).delete(key).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        (**self).rename(from, to).await
+    }
+
+    async fn read_versioned(&self, key: &str) -> Result<Option<(Bytes, String)>> {
+        (**self).read_versioned(key).await
+    }
+
+    async fn put_if_version(&self, key: &str, bytes: Bytes, version: Option<&str>) -> Result<()> {
+        (**self).put_if_version(key, bytes, version).await
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        (**self).list().await
+    }
+
+    async fn list_after(&self, offset: &str) -> Result<Vec<String>> {
+        (**self).list_after(offset).await
+    }
+
+    async fn health(&self) -> Result<()> {
+        (**self).health().await
+    }
+
+    async fn available_space(&self) -> Result<Option<u64>> {
+        (**self).available_space().await
+    }
+
+    async fn usage(&self) -> Result<u64> {
+        (**self).usage().await
+    }
+}
+
+/// Ensures the key is a single flat name and cannot escape the storage root.
+pub(crate) fn validate_key(key: &str) -> Result<()> {
+    if key.is_empty() || key.starts_with('.') || key.contains(['/', '\\']) || key.contains("..") {
+        return Err(Error::Parse(format!("invalid storage key `{}`", key)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_key() {
+        assert!(validate_key("abcdef.plugin").is_ok());
+        assert!(validate_key("").is_err());
+        assert!(validate_key("../abcdef.plugin").is_err());
+        assert!(validate_key("dir/abcdef.plugin").is_err());
+        assert!(validate_key("dir\\abcdef.plugin").is_err());
+    }
+}
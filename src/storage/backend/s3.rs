@@ -0,0 +1,335 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::{StreamExt, TryStreamExt};
+use object_store::{
+    aws::AmazonS3Builder, buffered::BufWriter, path::Path, ObjectStore, PutMode, PutOptions,
+    UpdateVersion,
+};
+use tokio::io::AsyncWriteExt;
+
+use crate::error::{Error, Result};
+
+use super::{validate_key, BlobInfo, BlobStore, BlobStream};
+
+/// Connection settings for an S3-compatible bucket
+#[derive(Clone, Default)]
+pub struct S3Config {
+    /// Custom endpoint for S3-compatible services (e.g. MinIO), defaults to AWS
+    pub endpoint: Option<String>,
+    /// Name of the bucket
+    pub bucket: String,
+    /// Optional path prefix under which all blobs are stored
+    pub prefix: Option<String>,
+    /// Region of the bucket
+    pub region: Option<String>,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    /// Allows plain http connections to the endpoint
+    pub allow_http: bool,
+}
+
+impl std::fmt::Debug for S3Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // never print the secret access key
+        f.debug_struct("S3Config")
+            .field("endpoint", &self.endpoint)
+            .field("bucket", &self.bucket)
+            .field("prefix", &self.prefix)
+            .field("region", &self.region)
+            .field("access_key_id", &self.access_key_id)
+            .field(
+                "secret_access_key",
+                &self.secret_access_key.as_ref().map(|_| "<redacted>"),
+            )
+            .field("allow_http", &self.allow_http)
+            .finish()
+    }
+}
+
+/// Stores all blobs as objects in an S3-compatible bucket.
+pub struct S3Store {
+    store: Arc<dyn ObjectStore>,
+    prefix: Path,
+}
+
+impl S3Store {
+    pub fn new(config: &S3Config) -> Result<Self> {
+        let mut builder = AmazonS3Builder::new()
+            .with_bucket_name(&config.bucket)
+            .with_region(config.region.as_deref().unwrap_or("us-east-1"))
+            .with_allow_http(config.allow_http);
+
+        if let Some(endpoint) = &config.endpoint {
+            // S3-compatible services usually do not support virtual hosted style requests
+            builder = builder
+                .with_endpoint(endpoint)
+                .with_virtual_hosted_style_request(false);
+        }
+        if let Some(access_key_id) = &config.access_key_id {
+            builder = builder.with_access_key_id(access_key_id);
+        }
+        if let Some(secret_access_key) = &config.secret_access_key {
+            builder = builder.with_secret_access_key(secret_access_key);
+        }
+
+        Self::with_object_store(
+            Arc::new(builder.build()?),
+            config.prefix.as_deref().unwrap_or_default(),
+        )
+    }
+
+    /// Creates a store on top of an existing object store implementation.
+    pub fn with_object_store(store: Arc<dyn ObjectStore>, prefix: &str) -> Result<Self> {
+        let prefix = Path::parse(prefix)
+            .map_err(|err| Error::Parse(format!("invalid object prefix: {}", err)))?;
+        Ok(Self { store, prefix })
+    }
+
+    fn path(&self, key: &str) -> Result<Path> {
+        validate_key(key)?;
+        Ok(self.prefix.child(key))
+    }
+}
+
+#[async_trait]
+impl BlobStore for S3Store {
+    async fn put(&self, key: &str, bytes: Bytes) -> Result<()> {
+        self.store.put(&self.path(key)?, bytes.into()).await?;
+        Ok(())
+    }
+
+    async fn put_file(&self, key: &str, path: &std::path::Path) -> Result<()> {
+        // large files are uploaded in multiple parts
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut writer = BufWriter::new(self.store.clone(), self.path(key)?);
+        tokio::io::copy(&mut file, &mut writer).await?;
+        writer.shutdown().await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<BlobStream> {
+        let result = self.store.get(&self.path(key)?).await?;
+        Ok(result.into_stream().map_err(Error::from).boxed())
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<BlobInfo>> {
+        match self.store.head(&self.path(key)?).await {
+            Ok(meta) => Ok(Some(BlobInfo {
+                size: meta.size,
+                modified: Some(meta.last_modified.into()),
+            })),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.store.delete(&self.path(key)?).await?;
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        // copies the object on the server side and removes the original
+        self.store
+            .rename(&self.path(from)?, &self.path(to)?)
+            .await?;
+        Ok(())
+    }
+
+    async fn read_versioned(&self, key: &str) -> Result<Option<(Bytes, String)>> {
+        match self.store.get(&self.path(key)?).await {
+            Ok(result) => {
+                let e_tag = result.meta.e_tag.clone().ok_or_else(|| {
+                    Error::ObjectStore(format!("object `{}` does not have an etag", key))
+                })?;
+                Ok(Some((result.bytes().await?, e_tag)))
+            }
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn put_if_version(&self, key: &str, bytes: Bytes, version: Option<&str>) -> Result<()> {
+        // requires a bucket which supports conditional requests, e.g. `If-Match` on AWS S3
+        let mode = match version {
+            Some(e_tag) => PutMode::Update(UpdateVersion {
+                e_tag: Some(e_tag.to_owned()),
+                version: None,
+            }),
+            None => PutMode::Create,
+        };
+        let options = PutOptions {
+            mode,
+            ..Default::default()
+        };
+        self.store
+            .put_opts(&self.path(key)?, bytes.into(), options)
+            .await?;
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let result = self.store.list_with_delimiter(Some(&self.prefix)).await?;
+        Ok(result
+            .objects
+            .into_iter()
+            .filter_map(|meta| meta.location.filename().map(str::to_owned))
+            .collect())
+    }
+
+    async fn list_after(&self, offset: &str) -> Result<Vec<String>> {
+        // the offset is passed on to S3, so the keys before it are not listed at all
+        let mut keys = self
+            .store
+            .list_with_offset(Some(&self.prefix), &self.path(offset)?)
+            .try_filter_map(|meta| {
+                let mut parts = meta
+                    .location
+                    .prefix_match(&self.prefix)
+                    .into_iter()
+                    .flatten();
+                // nested objects are not part of the store, see `list`
+                let key = match (parts.next(), parts.next()) {
+                    (Some(part), None) => Some(part.as_ref().to_owned()),
+                    _ => None,
+                };
+                futures_util::future::ready(Ok(key))
+            })
+            .try_collect::<Vec<_>>()
+            .await?;
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn health(&self) -> Result<()> {
+        // a cheap request that fails if the bucket is not accessible
+        self.store
+            .list(Some(&self.prefix))
+            .next()
+            .await
+            .transpose()?;
+        Ok(())
+    }
+
+    async fn usage(&self) -> Result<u64> {
+        // the listing already contains the object sizes
+        let result = self.store.list_with_delimiter(Some(&self.prefix)).await?;
+        Ok(result.objects.iter().map(|meta| meta.size).sum())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use object_store::memory::InMemory;
+
+    use super::*;
+
+    async fn put_get_delete(store: S3Store) {
+        store
+            .put("abcdef.plugin", Bytes::from_static(b"plugin"))
+            .await
+            .unwrap();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), b"{}").unwrap();
+        store.put_file("abcdef.meta", file.path()).await.unwrap();
+        assert_eq!(&store.read("abcdef.meta").await.unwrap()[..], b"{}");
+        assert_eq!(
+            store
+                .stat("abcdef.plugin")
+                .await
+                .unwrap()
+                .map(|info| info.size),
+            Some(6)
+        );
+        assert_eq!(&store.read("abcdef.plugin").await.unwrap()[..], b"plugin");
+
+        let mut keys = store.list().await.unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["abcdef.meta", "abcdef.plugin"]);
+        assert_eq!(
+            store.list_after("abcdef.meta").await.unwrap(),
+            vec!["abcdef.plugin"]
+        );
+
+        store
+            .rename("abcdef.plugin", "abcdef.plugin.corrupt")
+            .await
+            .unwrap();
+        assert_eq!(store.stat("abcdef.plugin").await.unwrap(), None);
+        assert_eq!(
+            &store.read("abcdef.plugin.corrupt").await.unwrap()[..],
+            b"plugin"
+        );
+        store
+            .rename("abcdef.plugin.corrupt", "abcdef.plugin")
+            .await
+            .unwrap();
+
+        // conditional writes fail once the object has been modified
+        store
+            .put_if_version("abcdef.tags", Bytes::from_static(b"a"), None)
+            .await
+            .unwrap();
+        let (_, version) = store.read_versioned("abcdef.tags").await.unwrap().unwrap();
+        store
+            .put_if_version("abcdef.tags", Bytes::from_static(b"b"), Some(&version))
+            .await
+            .unwrap();
+        assert!(matches!(
+            store
+                .put_if_version("abcdef.tags", Bytes::from_static(b"c"), Some(&version))
+                .await,
+            Err(Error::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            store
+                .put_if_version("abcdef.tags", Bytes::from_static(b"c"), None)
+                .await,
+            Err(Error::AlreadyExists(_))
+        ));
+        store.delete("abcdef.tags").await.unwrap();
+
+        store.delete("abcdef.plugin").await.unwrap();
+        store.delete("abcdef.meta").await.unwrap();
+        assert_eq!(store.stat("abcdef.plugin").await.unwrap(), None);
+        assert!(matches!(
+            store.get("abcdef.plugin").await,
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn prefixed_in_memory() {
+        let inner = Arc::new(InMemory::new());
+        inner
+            .put(&Path::from("unrelated.plugin"), Bytes::new().into())
+            .await
+            .unwrap();
+
+        let store = S3Store::with_object_store(inner, "registry/data").unwrap();
+        assert!(store.list().await.unwrap().is_empty());
+        put_get_delete(store).await;
+    }
+
+    /// Runs against a local S3-compatible server, e.g.:
+    /// `docker run -p 9000:9000 minio/minio server /data` with a bucket named `memflow-registry`
+    #[tokio::test]
+    #[ignore]
+    async fn minio() {
+        let config = S3Config {
+            endpoint: Some(
+                std::env::var("MEMFLOW_S3_ENDPOINT")
+                    .unwrap_or_else(|_| "http://127.0.0.1:9000".to_owned()),
+            ),
+            bucket: "memflow-registry".to_owned(),
+            prefix: Some("test".to_owned()),
+            access_key_id: Some("minioadmin".to_owned()),
+            secret_access_key: Some("minioadmin".to_owned()),
+            allow_http: true,
+            ..Default::default()
+        };
+        put_get_delete(S3Store::new(&config).unwrap()).await;
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+
+use log::info;
+
+use crate::{error::Result, rest::models::PluginInfo, storage::PluginMetadata};
+
+use super::{
+    filter_variants, variant_sort_key, PluginDatabase, PluginDatabaseFindParams, PluginVariant,
+};
+
+/// In-memory plugin database which has to be rebuilt from the storage on every start.
+#[derive(Default)]
+pub struct MemoryDatabase {
+    plugins: HashMap<String, Vec<PluginVariant>>,
+}
+
+impl MemoryDatabase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl PluginDatabase for MemoryDatabase {
+    fn insert_all(&mut self, metadata: &PluginMetadata) -> Result<()> {
+        for descriptor in metadata.descriptors.iter() {
+            info!(
+                "adding plugin variant to db: digest={}; created_at={}; descriptor={:?}",
+                metadata.digest, metadata.created_at, descriptor
+            );
+
+            let entry = self.plugins.entry(descriptor.name.clone()).or_default();
+
+            // sort by plugin_version, semantic version and created_at
+            // metadata is guaranteed to contain at least one descriptor and the plugin_version is identical for all connectors of a file.
+            let variant = PluginVariant::new(metadata, descriptor);
+            // variants with an equal key are ordered by insertion, newest first
+            let key = variant_sort_key(&variant);
+            let pos = entry.partition_point(|other| variant_sort_key(other) < key);
+            entry.insert(pos, variant);
+        }
+
+        Ok(())
+    }
+
+    fn plugins(&self) -> Result<Vec<PluginInfo>> {
+        let mut plugins = self
+            .plugins
+            .iter()
+            .flat_map(|(key, variants)| {
+                variants.iter().map(|variant| PluginInfo {
+                    name: key.to_owned(),
+                    description: variant.descriptor.description.clone(),
+                })
+            })
+            .collect::<Vec<_>>();
+        plugins.sort_by(|a, b| a.name.cmp(&b.name));
+        plugins.dedup_by(|a, b| a.name == b.name);
+        Ok(plugins)
+    }
+
+    fn find_by_digest(&self, digest: &str) -> Result<Option<PluginVariant>> {
+        Ok(self
+            .plugins
+            .iter()
+            .find_map(|(_, variants)| variants.iter().find(|variant| variant.digest == digest))
+            .cloned())
+    }
+
+    fn delete_by_digest(&mut self, digest: &str) -> Result<()> {
+        for plugin in self.plugins.iter_mut() {
+            plugin.1.retain(|variant| variant.digest != digest);
+        }
+        Ok(())
+    }
+
+    fn plugin_variants(
+        &self,
+        plugin_name: &str,
+        params: PluginDatabaseFindParams,
+    ) -> Result<Vec<PluginVariant>> {
+        Ok(self
+            .plugins
+            .get(plugin_name)
+            .map(|variants| filter_variants(plugin_name, variants.iter(), &params))
+            .unwrap_or_default())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.plugins.clear();
+        Ok(())
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.plugins.values().all(Vec::is_empty))
+    }
+
+    fn count(&self) -> Result<usize> {
+        Ok(self.plugins.values().map(Vec::len).sum())
+    }
+
+    fn digests(&self) -> Result<Vec<String>> {
+        let mut digests = self
+            .plugins
+            .values()
+            .flatten()
+            .map(|variant| variant.digest.clone())
+            .collect::<Vec<_>>();
+        digests.sort();
+        digests.dedup();
+        Ok(digests)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_database() {
+        crate::storage::database::tests::check_database(MemoryDatabase::new());
+    }
+}
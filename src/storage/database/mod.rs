@@ -0,0 +1,542 @@
+use std::cmp::Reverse;
+
+use chrono::NaiveDateTime;
+use memflow::plugins::plugin_analyzer::{PluginArchitecture, PluginDescriptorInfo, PluginFileType};
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{error::Result, rest::models::PluginInfo};
+
+use super::PluginMetadata;
+
+pub mod memory;
+pub mod sqlite;
+
+pub use memory::MemoryDatabase;
+pub use sqlite::SqliteDatabase;
+
+const DEFAULT_PLUGIN_VARIANTS: usize = 5;
+const MAX_PLUGIN_VARIANTS: usize = 50;
+
+/// A single plugin contained in a file
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct PluginVariant {
+    pub digest: String,
+    pub signature: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+    pub created_at: NaiveDateTime,
+    #[schema(value_type = crate::rest::models::PluginDescriptorInfoSchema)]
+    pub descriptor: PluginDescriptorInfo,
+    /// Timestamp at which the file was yanked, yanked variants can only be found by their digest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yanked_at: Option<NaiveDateTime>,
+}
+
+impl PluginVariant {
+    /// Creates the variant of a single descriptor of the file
+    pub fn new(metadata: &PluginMetadata, descriptor: &PluginDescriptorInfo) -> Self {
+        Self {
+            digest: metadata.digest.clone(),
+            signature: metadata.signature.clone(),
+            key_id: metadata.key_id.clone(),
+            created_at: metadata.created_at,
+            descriptor: descriptor.clone(),
+            yanked_at: metadata.yanked_at,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PluginDatabaseFindParams {
+    /// Plugin version, semantic version requirement, tag or digest prefix
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// The memflow abi version
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memflow_plugin_version: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[param(value_type = Option<crate::rest::models::PluginFileTypeSchema>)]
+    pub file_type: Option<PluginFileType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[param(value_type = Option<crate::rest::models::PluginArchitectureSchema>)]
+    pub architecture: Option<PluginArchitecture>,
+    /// Restricts the search to these digests, set when resolving a tag
+    #[serde(skip)]
+    #[param(ignore)]
+    pub digests: Option<Vec<String>>,
+
+    // pagination parameters
+    /// Skips the first `skip` variants
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip: Option<usize>,
+    /// Maximum number of variants returned
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+/// Index of all plugin variants in the storage.
+///
+/// Variants of a plugin are ordered by their plugin_version, semantic version and created_at timestamp,
+/// so the newest version of each plugin_version comes first.
+/// Versions which are not valid semantic versions are ordered after all valid ones.
+pub trait PluginDatabase: Send + Sync {
+    /// Inserts all plugin variants of this file into the database
+    fn insert_all(&mut self, metadata: &PluginMetadata) -> Result<()>;
+
+    /// Returns a list of all plugin names and their descriptions.
+    fn plugins(&self) -> Result<Vec<PluginInfo>>;
+
+    /// Retrieves a specific digest
+    #[allow(unused)]
+    fn find_by_digest(&self, digest: &str) -> Result<Option<PluginVariant>>;
+
+    /// Removes all entries with the specified digest from the database
+    fn delete_by_digest(&mut self, digest: &str) -> Result<()>;
+
+    /// Retrieves a list of variants for a specific plugin.
+    /// Additional search parameters can be specified.
+    fn plugin_variants(
+        &self,
+        plugin_name: &str,
+        params: PluginDatabaseFindParams,
+    ) -> Result<Vec<PluginVariant>>;
+
+    /// Removes all entries from the database
+    fn clear(&mut self) -> Result<()>;
+
+    /// Returns true if the database does not contain any plugin variants
+    fn is_empty(&self) -> Result<bool>;
+
+    /// Returns the number of plugin variants in the database
+    fn count(&self) -> Result<usize>;
+
+    /// Returns the digests of all files in the database
+    fn digests(&self) -> Result<Vec<String>>;
+}
+
+impl<T: PluginDatabase + ?Sized> PluginDatabase for Box<T> {
+    fn insert_all(&mut self, metadata: &PluginMetadata) -> Result<()> {
+        (**self).insert_all(metadata)
+    }
+
+    fn plugins(&self) -> Result<Vec<PluginInfo>> {
+        (**self).plugins()
+    }
+
+    fn find_by_digest(&self, digest: &str) -> Result<Option<PluginVariant>> {
+        (**self).find_by_digest(digest)
+    }
+
+    fn delete_by_digest(&mut self, digest: &str) -> Result<()> {
+        (**self).delete_by_digest(digest)
+    }
+
+    fn plugin_variants(
+        &self,
+        plugin_name: &str,
+        params: PluginDatabaseFindParams,
+    ) -> Result<Vec<PluginVariant>> {
+        (**self).plugin_variants(plugin_name, params)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        (**self).clear()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        (**self).is_empty()
+    }
+
+    fn count(&self) -> Result<usize> {
+        (**self).count()
+    }
+
+    fn digests(&self) -> Result<Vec<String>> {
+        (**self).digests()
+    }
+}
+
+/// Returns the key by which variants of a plugin are sorted (in reverse).
+#[inline]
+fn variant_sort_key(variant: &PluginVariant) -> Reverse<(i32, Option<Version>, NaiveDateTime)> {
+    Reverse((
+        variant.descriptor.plugin_version,
+        Version::parse(&variant.descriptor.version).ok(),
+        variant.created_at,
+    ))
+}
+
+/// Parses the `version` parameter as a semantic version requirement like `^0.2` or `>=0.2, <0.3`.
+///
+/// Plain versions like `0.2.0` are still matched exactly and are not treated as a requirement.
+fn version_requirement(version: &str) -> Option<VersionReq> {
+    if Version::parse(version).is_ok() {
+        return None;
+    }
+    VersionReq::parse(version).ok()
+}
+
+/// Applies the search parameters and pagination to the sorted variants of a plugin.
+fn filter_variants<'a>(
+    plugin_name: &str,
+    variants: impl Iterator<Item = &'a PluginVariant>,
+    params: &PluginDatabaseFindParams,
+) -> Vec<PluginVariant> {
+    let requirement = params.version.as_deref().and_then(version_requirement);
+    variants
+        .skip(params.skip.unwrap_or(0))
+        .filter(|p| p.descriptor.name == plugin_name)
+        .filter(|p| {
+            let digest_matches = params
+                .version
+                .as_ref()
+                .is_some_and(|version| p.digest.starts_with(version.as_str()));
+
+            // yanked variants are only returned when they are requested by their digest
+            if p.yanked_at.is_some() && !digest_matches {
+                return false;
+            }
+
+            if let Some(version) = &params.version {
+                // version can match the version directly, a version requirement or the corresponding digest
+                let requirement_matches = requirement.as_ref().is_some_and(|requirement| {
+                    Version::parse(&p.descriptor.version)
+                        .is_ok_and(|version| requirement.matches(&version))
+                });
+                if *version != p.descriptor.version && !requirement_matches && !digest_matches {
+                    return false;
+                }
+            }
+
+            if let Some(digests) = &params.digests {
+                if !digests.contains(&p.digest) {
+                    return false;
+                }
+            }
+
+            if let Some(memflow_plugin_version) = params.memflow_plugin_version {
+                if memflow_plugin_version != p.descriptor.plugin_version {
+                    return false;
+                }
+            }
+
+            if let Some(file_type) = params.file_type {
+                if file_type != p.descriptor.file_type {
+                    return false;
+                }
+            }
+
+            if let Some(architecture) = params.architecture {
+                if architecture != p.descriptor.architecture {
+                    return false;
+                }
+            }
+
+            true
+        })
+        .take(
+            params
+                .limit
+                .unwrap_or(DEFAULT_PLUGIN_VARIANTS)
+                .min(MAX_PLUGIN_VARIANTS),
+        )
+        .cloned()
+        .collect::<Vec<_>>()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use bytes::Bytes;
+    use chrono::NaiveDate;
+    use memflow::plugins::plugin_analyzer::PluginKind;
+    use tokio::task::JoinHandle;
+
+    use crate::{
+        pki::SignatureGenerator,
+        rest::{middlewares::ApiTokens, routes::app},
+        storage::{backend::BlobStore, meta_key, plugin_key, Storage},
+    };
+
+    use super::*;
+
+    pub fn metadata(
+        digest: &str,
+        name: &str,
+        version: &str,
+        plugin_version: i32,
+        day: u32,
+    ) -> PluginMetadata {
+        PluginMetadata {
+            digest: digest.to_owned(),
+            signature: "00".to_owned(),
+            signature_scheme: Default::default(),
+            key_id: None,
+            created_at: NaiveDate::from_ymd_opt(2024, 1, day)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+            descriptors: vec![PluginDescriptorInfo {
+                plugin_kind: PluginKind::Connector,
+                export_name: format!("MEMFLOW_CONNECTOR_{}", name.to_uppercase()),
+                file_type: PluginFileType::Elf,
+                architecture: PluginArchitecture::X86_64,
+                plugin_version,
+                name: name.to_owned(),
+                version: version.to_owned(),
+                description: format!("{} connector", name),
+            }],
+            quarantined_at: None,
+            deleted_at: None,
+            yanked_at: None,
+        }
+    }
+
+    /// Returns a real connector binary which passes the plugin analyzer, see `tests/fixtures/connector.c`.
+    pub fn plugin_binary() -> Bytes {
+        Bytes::from_static(include_bytes!(
+            "../../../tests/fixtures/connector.x86_64.so"
+        ))
+    }
+
+    /// Returns the (non-binary) contents of the file stored by [`put_plugin`].
+    pub fn plugin_contents(name: &str, version: &str) -> Bytes {
+        format!("{} {} plugin", name, version).into()
+    }
+
+    /// Stores a file for the given plugin version together with its metadata and returns its digest.
+    pub async fn put_plugin(backend: &dyn BlobStore, name: &str, version: &str) -> String {
+        put_file(backend, name, version, None).await
+    }
+
+    /// Same as [`put_plugin`] but the file is signed with the given key.
+    pub async fn put_signed_plugin(
+        backend: &dyn BlobStore,
+        name: &str,
+        version: &str,
+        generator: &mut SignatureGenerator,
+    ) -> String {
+        put_file(backend, name, version, Some(generator)).await
+    }
+
+    async fn put_file(
+        backend: &dyn BlobStore,
+        name: &str,
+        version: &str,
+        generator: Option<&mut SignatureGenerator>,
+    ) -> String {
+        let contents = plugin_contents(name, version);
+        let digest = sha256::digest(&contents[..]);
+        let mut meta = metadata(&digest, name, version, 1, 1);
+        if let Some(generator) = generator {
+            meta.signature = generator.sign(&contents).unwrap();
+        }
+        backend.put(&plugin_key(&digest), contents).await.unwrap();
+        backend
+            .put(
+                &meta_key(&digest),
+                serde_json::to_vec(&meta).unwrap().into(),
+            )
+            .await
+            .unwrap();
+        digest
+    }
+
+    /// Serves the rest api of the storage on a random local port and returns its url.
+    pub async fn serve(storage: Storage) -> String {
+        serve_router(app(storage, ApiTokens::default(), Default::default()))
+            .await
+            .0
+    }
+
+    /// Serves the router on a random local port and returns its url and the server task.
+    pub async fn serve_router(router: axum::Router) -> (String, JoinHandle<()>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        (url, server)
+    }
+
+    /// Runs the same set of checks against all database implementations
+    pub fn check_database<D: PluginDatabase>(mut database: D) {
+        assert!(database.is_empty().unwrap());
+
+        database
+            .insert_all(&metadata("aaaa", "coredump", "0.2.0", 1, 1))
+            .unwrap();
+        database
+            .insert_all(&metadata("bbbb", "coredump", "0.2.1", 1, 3))
+            .unwrap();
+        database
+            .insert_all(&metadata("cccc", "coredump", "0.1.0", 0, 5))
+            .unwrap();
+        database
+            .insert_all(&metadata("dddd", "qemu", "0.2.0", 1, 2))
+            .unwrap();
+        assert!(!database.is_empty().unwrap());
+        assert_eq!(database.count().unwrap(), 4);
+        let mut digests = database.digests().unwrap();
+        digests.sort();
+        assert_eq!(digests, vec!["aaaa", "bbbb", "cccc", "dddd"]);
+
+        let plugins = database.plugins().unwrap();
+        assert_eq!(
+            plugins.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(),
+            vec!["coredump", "qemu"]
+        );
+
+        let digests = |variants: Vec<PluginVariant>| {
+            variants.into_iter().map(|v| v.digest).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            digests(
+                database
+                    .plugin_variants("coredump", Default::default())
+                    .unwrap()
+            ),
+            vec!["bbbb", "aaaa", "cccc"]
+        );
+        assert_eq!(
+            digests(
+                database
+                    .plugin_variants(
+                        "coredump",
+                        PluginDatabaseFindParams {
+                            version: Some("aa".to_owned()),
+                            ..Default::default()
+                        }
+                    )
+                    .unwrap()
+            ),
+            vec!["aaaa"]
+        );
+        assert_eq!(
+            digests(
+                database
+                    .plugin_variants(
+                        "coredump",
+                        PluginDatabaseFindParams {
+                            memflow_plugin_version: Some(0),
+                            ..Default::default()
+                        }
+                    )
+                    .unwrap()
+            ),
+            vec!["cccc"]
+        );
+        assert_eq!(
+            digests(
+                database
+                    .plugin_variants(
+                        "coredump",
+                        PluginDatabaseFindParams {
+                            skip: Some(1),
+                            limit: Some(1),
+                            ..Default::default()
+                        }
+                    )
+                    .unwrap()
+            ),
+            vec!["aaaa"]
+        );
+
+        // yanked variants are hidden unless requested by digest
+        database.delete_by_digest("bbbb").unwrap();
+        let mut yanked = metadata("bbbb", "coredump", "0.2.1", 1, 3);
+        yanked.yanked_at = Some(yanked.created_at);
+        database.insert_all(&yanked).unwrap();
+        assert_eq!(
+            digests(
+                database
+                    .plugin_variants("coredump", Default::default())
+                    .unwrap()
+            ),
+            vec!["aaaa", "cccc"]
+        );
+        for version in ["0.2.1", "bb"] {
+            let variants = database
+                .plugin_variants(
+                    "coredump",
+                    PluginDatabaseFindParams {
+                        version: Some(version.to_owned()),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            let expected: &[&str] = if version == "bb" { &["bbbb"] } else { &[] };
+            assert_eq!(digests(variants), expected);
+        }
+
+        // versions are ordered semantically within a plugin_version
+        database
+            .insert_all(&metadata("eeee", "coredump", "0.10.0", 1, 2))
+            .unwrap();
+        assert_eq!(
+            digests(
+                database
+                    .plugin_variants("coredump", Default::default())
+                    .unwrap()
+            ),
+            vec!["eeee", "aaaa", "cccc"]
+        );
+        for (requirement, expected) in [
+            ("^0.2", vec!["aaaa"]),
+            ("~0.1", vec!["cccc"]),
+            (">=0.2, <0.11", vec!["eeee", "aaaa"]),
+            ("<1", vec!["eeee", "aaaa", "cccc"]),
+        ] {
+            assert_eq!(
+                digests(
+                    database
+                        .plugin_variants(
+                            "coredump",
+                            PluginDatabaseFindParams {
+                                version: Some(requirement.to_owned()),
+                                ..Default::default()
+                            }
+                        )
+                        .unwrap()
+                ),
+                expected
+            );
+        }
+
+        assert_eq!(
+            database
+                .find_by_digest("dddd")
+                .unwrap()
+                .unwrap()
+                .descriptor
+                .name,
+            "qemu"
+        );
+        database.delete_by_digest("dddd").unwrap();
+        assert!(database.find_by_digest("dddd").unwrap().is_none());
+        assert!(database
+            .plugin_variants("qemu", Default::default())
+            .unwrap()
+            .is_empty());
+
+        // the description is taken from the semantically latest version, equal versions do not conflict
+        let mut latest = metadata("ffff", "coredump", "0.10.0", 1, 2);
+        latest.descriptors[0].description = "latest coredump connector".to_owned();
+        database.insert_all(&latest).unwrap();
+        let plugins = database.plugins().unwrap();
+        assert_eq!(plugins.len(), 1);
+        assert_eq!(plugins[0].description, "latest coredump connector");
+        assert_eq!(
+            digests(
+                database
+                    .plugin_variants("coredump", Default::default())
+                    .unwrap()
+            ),
+            vec!["ffff", "eeee", "aaaa", "cccc"]
+        );
+
+        database.clear().unwrap();
+        assert!(database.is_empty().unwrap());
+        assert_eq!(database.count().unwrap(), 0);
+    }
+}
@@ -0,0 +1,281 @@
+use std::path::Path;
+
+use log::info;
+use parking_lot::Mutex;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{error::Result, rest::models::PluginInfo, storage::PluginMetadata};
+
+use super::{
+    filter_variants, variant_sort_key, PluginDatabase, PluginDatabaseFindParams, PluginVariant,
+};
+
+/// Schema migrations, the n-th entry migrates the database to `user_version` n+1.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema
+    "CREATE TABLE plugin_variants (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        digest TEXT NOT NULL,
+        name TEXT NOT NULL,
+        description TEXT NOT NULL,
+        plugin_version INTEGER NOT NULL,
+        created_at INTEGER NOT NULL,
+        variant TEXT NOT NULL
+    );
+    CREATE INDEX plugin_variants_name ON plugin_variants (name, plugin_version DESC, created_at DESC);
+    CREATE INDEX plugin_variants_digest ON plugin_variants (digest);",
+];
+
+/// Persistent plugin database stored in a SQLite file.
+///
+/// Only the variants of a single plugin are loaded into memory when querying.
+pub struct SqliteDatabase {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteDatabase {
+    /// Opens or creates the database file at the given path and applies all pending migrations.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        Self::with_connection(connection)
+    }
+
+    /// Creates a temporary database which only lives in memory.
+    pub fn open_in_memory() -> Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(mut connection: Connection) -> Result<Self> {
+        migrate(&mut connection)?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+fn migrate(connection: &mut Connection) -> Result<()> {
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!("migrating plugin database to version {}", idx + 1);
+        let tx = connection.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", idx + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+impl PluginDatabase for SqliteDatabase {
+    fn insert_all(&mut self, metadata: &PluginMetadata) -> Result<()> {
+        let mut connection = self.connection.lock();
+        let tx = connection.transaction()?;
+        for descriptor in metadata.descriptors.iter() {
+            info!(
+                "adding plugin variant to db: digest={}; created_at={}; descriptor={:?}",
+                metadata.digest, metadata.created_at, descriptor
+            );
+
+            let variant = PluginVariant::new(metadata, descriptor);
+            tx.execute(
+                "INSERT INTO plugin_variants (digest, name, description, plugin_version, created_at, variant)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    variant.digest,
+                    variant.descriptor.name,
+                    variant.descriptor.description,
+                    variant.descriptor.plugin_version,
+                    variant.created_at.and_utc().timestamp_micros(),
+                    serde_json::to_string(&variant)?,
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn plugins(&self) -> Result<Vec<PluginInfo>> {
+        let connection = self.connection.lock();
+        // the description is taken from the latest variant of each plugin,
+        // semantic versions are not ordered by sqlite so the variants are compared here
+        let mut stmt = connection
+            .prepare_cached("SELECT name, variant FROM plugin_variants ORDER BY name, id DESC")?;
+        let mut rows = stmt.query([])?;
+        let mut plugins: Vec<(PluginInfo, _)> = Vec::new();
+        while let Some(row) = rows.next()? {
+            let name: String = row.get(0)?;
+            let variant = serde_json::from_str::<PluginVariant>(&row.get::<_, String>(1)?)?;
+            let key = variant_sort_key(&variant);
+            match plugins.last_mut() {
+                Some((plugin, latest)) if plugin.name == name => {
+                    if key < *latest {
+                        plugin.description = variant.descriptor.description;
+                        *latest = key;
+                    }
+                }
+                _ => plugins.push((
+                    PluginInfo {
+                        name,
+                        description: variant.descriptor.description,
+                    },
+                    key,
+                )),
+            }
+        }
+        Ok(plugins.into_iter().map(|(plugin, _)| plugin).collect())
+    }
+
+    fn find_by_digest(&self, digest: &str) -> Result<Option<PluginVariant>> {
+        let connection = self.connection.lock();
+        let variant: Option<String> = connection
+            .prepare_cached("SELECT variant FROM plugin_variants WHERE digest = ?1 LIMIT 1")?
+            .query_row([digest], |row| row.get(0))
+            .optional()?;
+        Ok(variant
+            .map(|variant| serde_json::from_str(&variant))
+            .transpose()?)
+    }
+
+    fn delete_by_digest(&mut self, digest: &str) -> Result<()> {
+        self.connection
+            .lock()
+            .execute("DELETE FROM plugin_variants WHERE digest = ?1", [digest])?;
+        Ok(())
+    }
+
+    fn plugin_variants(
+        &self,
+        plugin_name: &str,
+        params: PluginDatabaseFindParams,
+    ) -> Result<Vec<PluginVariant>> {
+        let connection = self.connection.lock();
+        let mut stmt = connection.prepare_cached(
+            "SELECT variant FROM plugin_variants WHERE name = ?1
+            ORDER BY plugin_version DESC, created_at DESC, id DESC",
+        )?;
+        let mut variants = stmt
+            .query_map([plugin_name], |row| row.get::<_, String>(0))?
+            .map(|variant| Ok(serde_json::from_str::<PluginVariant>(&variant?)?))
+            .collect::<Result<Vec<_>>>()?;
+        // semantic versions are not ordered by sqlite, the sort is stable so ties keep the insertion order
+        variants.sort_by_cached_key(variant_sort_key);
+        Ok(filter_variants(plugin_name, variants.iter(), &params))
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.connection
+            .lock()
+            .execute("DELETE FROM plugin_variants", [])?;
+        Ok(())
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        let connection = self.connection.lock();
+        let exists: bool =
+            connection.query_row("SELECT EXISTS (SELECT 1 FROM plugin_variants)", [], |row| {
+                row.get(0)
+            })?;
+        Ok(!exists)
+    }
+
+    fn count(&self) -> Result<usize> {
+        let connection = self.connection.lock();
+        let count: i64 =
+            connection.query_row("SELECT COUNT(*) FROM plugin_variants", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    fn digests(&self) -> Result<Vec<String>> {
+        let connection = self.connection.lock();
+        let mut statement = connection.prepare("SELECT DISTINCT digest FROM plugin_variants")?;
+        let digests = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<std::result::Result<Vec<String>, _>>()?;
+        Ok(digests)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sqlite_database() {
+        crate::storage::database::tests::check_database(SqliteDatabase::open_in_memory().unwrap());
+    }
+
+    #[test]
+    fn sqlite_database_persists() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("index.db");
+
+        let mut database = SqliteDatabase::open(&path).unwrap();
+        database
+            .insert_all(&crate::storage::database::tests::metadata(
+                "aaaa", "coredump", "0.2.0", 1, 1,
+            ))
+            .unwrap();
+        drop(database);
+
+        // reopening does not re-apply migrations and keeps the entries
+        let database = SqliteDatabase::open(&path).unwrap();
+        assert!(database.find_by_digest("aaaa").unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn reconcile_on_startup() {
+        use crate::storage::{
+            backend::{BlobStore, MemoryStore},
+            database::tests::put_plugin,
+            meta_key, plugin_key, PluginMetadata, Storage,
+        };
+
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("index.db");
+        let backend = std::sync::Arc::new(MemoryStore::new());
+        let removed = put_plugin(&backend, "coredump", "0.2.0").await;
+        let storage = Storage::with_database(backend.clone(), SqliteDatabase::open(&path).unwrap())
+            .await
+            .unwrap();
+        assert!(storage
+            .database()
+            .find_by_digest(&removed)
+            .unwrap()
+            .is_some());
+        drop(storage);
+
+        // files changed by other instances while the registry was not running
+        let added = put_plugin(&backend, "coredump", "0.2.1").await;
+        backend.delete(&plugin_key(&removed)).await.unwrap();
+
+        // files in the trash are not part of the database
+        let trashed = put_plugin(&backend, "coredump", "0.1.0").await;
+        let mut metadata: PluginMetadata =
+            serde_json::from_slice(&backend.read(&meta_key(&trashed)).await.unwrap()).unwrap();
+        metadata.deleted_at = Some(chrono::Utc::now().naive_utc());
+        backend
+            .put(
+                &meta_key(&trashed),
+                serde_json::to_vec(&metadata).unwrap().into(),
+            )
+            .await
+            .unwrap();
+
+        let storage = Storage::with_database(backend, SqliteDatabase::open(&path).unwrap())
+            .await
+            .unwrap();
+        assert!(storage
+            .database()
+            .find_by_digest(&removed)
+            .unwrap()
+            .is_none());
+        assert!(storage.database().find_by_digest(&added).unwrap().is_some());
+        assert!(storage
+            .database()
+            .find_by_digest(&trashed)
+            .unwrap()
+            .is_none());
+        assert_eq!(storage.reconcile_database().await.unwrap(), 0);
+    }
+}
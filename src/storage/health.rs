@@ -0,0 +1,303 @@
+//! Readiness checks of the storage and its dependencies
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::error::{Error, Result};
+
+use super::{backend::filesystem::available_space, Storage};
+
+/// Default minimum of free disk space, the registry is not ready to accept uploads below it
+pub const DEFAULT_MIN_FREE_SPACE: u64 = 1024 * 1024 * 1024; // 1 gb
+
+/// Maximum time a single check may take before it is considered failed
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Interval in which the index check should be refreshed in the background,
+/// it lists the entire backend and is therefore not run on every readiness probe
+pub const INDEX_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Result of a single check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    /// The registry works but is not configured as recommended
+    Warn,
+    Fail,
+}
+
+/// A single named check
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct HealthCheck {
+    pub name: String,
+    pub status: CheckStatus,
+    /// Human-readable details of the check
+    pub message: String,
+}
+
+/// Last result of the index check
+#[derive(Debug, Default)]
+pub(crate) struct IndexCheck {
+    result: Option<HealthCheck>,
+    /// Files that were missing in the previous check, the check only fails if they are still missing
+    missing: HashSet<String>,
+}
+
+/// Results of all readiness checks
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ReadinessReport {
+    /// True if none of the checks failed
+    pub ready: bool,
+    pub checks: Vec<HealthCheck>,
+}
+
+impl Storage {
+    /// Sets the minimum of free disk space below which the storage is not ready.
+    pub fn with_min_free_space(mut self, min_free_space: u64) -> Self {
+        self.min_free_space = min_free_space;
+        self
+    }
+
+    /// Checks if the storage is ready to serve requests.
+    ///
+    /// The storage has to be writable, have enough free disk space and an index which matches the stored files.
+    /// In pull-through mode the upstream registry has to be reachable as well.
+    pub async fn readiness(&self) -> ReadinessReport {
+        let mut checks = vec![
+            check("storage_writable", self.check_writable()).await,
+            check("disk_space", self.check_disk_space()).await,
+            self.cached_index_check().await,
+            self.check_verifier(),
+        ];
+        if let Some(upstream) = &self.upstream {
+            let registry = upstream.registry().to_owned();
+            checks.push(
+                check("upstream", async move {
+                    upstream.health().await?;
+                    Ok(format!("{} is reachable", registry))
+                })
+                .await,
+            );
+        }
+
+        ReadinessReport {
+            ready: checks.iter().all(|check| check.status != CheckStatus::Fail),
+            checks,
+        }
+    }
+
+    /// Writes, reads and removes a temporary blob.
+    async fn check_writable(&self) -> Result<String> {
+        // other instances might probe the same storage concurrently
+        let seed = format!("{:?}-{}", SystemTime::now(), std::process::id());
+        let key = format!("{}.probe", &sha256::digest(seed)[..16]);
+
+        self.backend.put(&key, Bytes::from_static(b"ok")).await?;
+        let contents = self.backend.read(&key).await;
+        self.backend.delete(&key).await?;
+        if contents? != b"ok"[..] {
+            return Err(Error::IO("probe contents do not match".to_owned()));
+        }
+        Ok("storage is writable".to_owned())
+    }
+
+    /// Checks the free space of the storage and the upload directory.
+    async fn check_disk_space(&self) -> Result<String> {
+        let upload_dir = self.upload_dir.clone();
+        let free = [
+            self.backend.available_space().await?,
+            tokio::task::spawn_blocking(move || available_space(&upload_dir)).await??,
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+
+        match free {
+            Some(free) if free < self.min_free_space => Err(Error::TooLarge(format!(
+                "only {} bytes free, at least {} bytes are required",
+                free, self.min_free_space
+            ))),
+            Some(free) => Ok(format!("{} bytes free", free)),
+            None => Ok("storage is not limited by a local disk".to_owned()),
+        }
+    }
+
+    /// Returns the last result of the index check or runs it if it has not been run yet.
+    async fn cached_index_check(&self) -> HealthCheck {
+        if let Some(cached) = self.index_check.read().result.clone() {
+            return cached;
+        }
+        self.refresh_index_check().await
+    }
+
+    /// Lists the backend, checks the index against it and caches the result for readiness probes.
+    pub async fn refresh_index_check(&self) -> HealthCheck {
+        let result = check("index", async {
+            // files uploaded while listing are not part of the index yet and can not be reported as missing
+            let (variants, digests) = self.index_snapshot()?;
+            let keys = self.backend.list().await?;
+            self.check_index(variants, &digests, &keys)
+        })
+        .await;
+        self.index_check.write().result = Some(result.clone());
+        result
+    }
+
+    /// Caches the result of the index check against keys which have been listed by another pass.
+    pub(crate) fn update_index_check(&self, keys: &[String]) {
+        let (status, message) = match self
+            .index_snapshot()
+            .and_then(|(variants, digests)| self.check_index(variants, &digests, keys))
+        {
+            Ok(message) => (CheckStatus::Pass, message),
+            Err(err) => (CheckStatus::Fail, err.to_string()),
+        };
+        self.index_check.write().result = Some(HealthCheck {
+            name: "index".to_owned(),
+            status,
+            message,
+        });
+    }
+
+    /// Returns the number of indexed variants and the digests of all indexed files.
+    fn index_snapshot(&self) -> Result<(usize, Vec<String>)> {
+        let database = self.database.read();
+        Ok((database.count()?, database.digests()?))
+    }
+
+    /// Ensures every indexed file is still present in the given keys of the storage.
+    ///
+    /// Files can be deleted between taking the snapshot of the index and listing the storage,
+    /// so the check only fails for files which have been missing in two consecutive checks.
+    fn check_index(&self, variants: usize, digests: &[String], keys: &[String]) -> Result<String> {
+        let stored = keys
+            .iter()
+            .filter_map(|key| key.strip_suffix(".plugin"))
+            .collect::<HashSet<_>>();
+
+        let missing = digests
+            .iter()
+            .filter(|digest| !stored.contains(digest.as_str()))
+            .cloned()
+            .collect::<HashSet<_>>();
+        let previous = std::mem::replace(&mut self.index_check.write().missing, missing.clone());
+        let confirmed = missing.intersection(&previous).count();
+        if confirmed > 0 {
+            return Err(Error::NotFound(format!(
+                "{} indexed files are missing from the storage",
+                confirmed
+            )));
+        }
+
+        let mut message = format!(
+            "{} variants of {} files indexed, {} files stored",
+            variants,
+            digests.len(),
+            stored.len()
+        );
+        if !missing.is_empty() {
+            message.push_str(&format!(
+                ", {} files are missing and will be checked again",
+                missing.len()
+            ));
+        }
+        Ok(message)
+    }
+
+    /// Warns if uploads are accepted without verifying their signatures.
+    fn check_verifier(&self) -> HealthCheck {
+        match &self.keyring {
+            Some(keyring) => HealthCheck {
+                name: "verifier".to_owned(),
+                status: CheckStatus::Pass,
+                message: format!("{} trusted keys", keyring.key_ids().count()),
+            },
+            None => HealthCheck {
+                name: "verifier".to_owned(),
+                status: CheckStatus::Warn,
+                message: "signatures of uploaded files are not verified".to_owned(),
+            },
+        }
+    }
+}
+
+/// Runs a single check with a timeout.
+async fn check<F: Future<Output = Result<String>>>(name: &str, check: F) -> HealthCheck {
+    let (status, message) = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(Ok(message)) => (CheckStatus::Pass, message),
+        Ok(Err(err)) => (CheckStatus::Fail, err.to_string()),
+        Err(_) => (CheckStatus::Fail, "check timed out".to_owned()),
+    };
+    HealthCheck {
+        name: name.to_owned(),
+        status,
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{
+        backend::{BlobStore, MemoryStore},
+        database::tests::put_plugin,
+        plugin_key,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn readiness() {
+        let backend = std::sync::Arc::new(MemoryStore::new());
+        let digest = put_plugin(&backend, "coredump", "0.2.0").await;
+        let storage = Storage::with_backend(backend.clone())
+            .await
+            .unwrap()
+            .with_min_free_space(0);
+
+        let report = storage.readiness().await;
+        assert!(report.ready);
+        let status = |report: &ReadinessReport, name: &str| {
+            report
+                .checks
+                .iter()
+                .find(|check| check.name == name)
+                .unwrap()
+                .status
+        };
+        assert_eq!(status(&report, "storage_writable"), CheckStatus::Pass);
+        assert_eq!(status(&report, "index"), CheckStatus::Pass);
+        assert_eq!(status(&report, "verifier"), CheckStatus::Warn);
+        assert!(!report.checks.iter().any(|check| check.name == "upstream"));
+
+        // the probe does not leave anything behind
+        assert_eq!(backend.list().await.unwrap().len(), 2);
+
+        // files removed behind the back of the registry make the index inconsistent
+        // once they have been missing in two consecutive refreshes of the cached index check
+        backend.delete(&plugin_key(&digest)).await.unwrap();
+        assert!(storage.readiness().await.ready);
+        assert_eq!(
+            storage.refresh_index_check().await.status,
+            CheckStatus::Pass
+        );
+        assert!(storage.readiness().await.ready);
+        assert_eq!(
+            storage.refresh_index_check().await.status,
+            CheckStatus::Fail
+        );
+        let report = storage.readiness().await;
+        assert!(!report.ready);
+        assert_eq!(status(&report, "index"), CheckStatus::Fail);
+
+        if cfg!(unix) {
+            let report = storage.with_min_free_space(u64::MAX).readiness().await;
+            assert_eq!(status(&report, "disk_space"), CheckStatus::Fail);
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! Change journal to keep the databases of multiple instances sharing the same backend in sync.
+//!
+//! Every instance that modifies a file writes an empty `~journal-{timestamp}-{instance}-{digest}` blob.
+//! All instances periodically list the journal entries and reload the state of every digest
+//! referenced by an entry they have not seen yet. Entries are removed after the retention period.
+//!
+//! The prefix sorts after all digests, so the entries can be listed without listing the stored plugins.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use log::{info, warn};
+use parking_lot::Mutex;
+
+use crate::error::{Error, Result, ResultExt};
+
+use super::{plugin_key, PluginMetadata, Storage};
+
+const JOURNAL_PREFIX: &str = "~journal-";
+
+/// Journal configuration and state of this instance
+#[derive(Clone)]
+pub struct Journal {
+    instance_id: String,
+    retention: Duration,
+    seen: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Journal {
+    /// Creates a new journal for this instance.
+    /// The instance id must be unique across all instances sharing the same backend.
+    pub fn new(instance_id: &str, retention: Duration) -> Result<Self> {
+        if instance_id.is_empty() || !instance_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(Error::Parse(
+                "instance id must only contain alphanumeric characters".to_owned(),
+            ));
+        }
+
+        Ok(Self {
+            instance_id: instance_id.to_owned(),
+            retention,
+            seen: Arc::new(Mutex::new(HashSet::new())),
+        })
+    }
+
+    /// Creates a new journal with a random instance id.
+    pub fn with_random_id(retention: Duration) -> Self {
+        let seed = format!("{:?}-{}", SystemTime::now(), std::process::id());
+        Self::new(&sha256::digest(seed)[..16], retention).unwrap()
+    }
+
+    #[inline]
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+}
+
+/// A parsed journal entry key
+struct JournalEntry<'a> {
+    timestamp: u128,
+    instance_id: &'a str,
+    digest: &'a str,
+}
+
+impl<'a> JournalEntry<'a> {
+    fn parse(key: &'a str) -> Option<Self> {
+        let mut parts = key.strip_prefix(JOURNAL_PREFIX)?.splitn(3, '-');
+        Some(Self {
+            timestamp: parts.next()?.parse().ok()?,
+            instance_id: parts.next()?,
+            digest: parts.next()?,
+        })
+    }
+}
+
+#[inline]
+fn now_micros() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros()
+}
+
+impl Storage {
+    /// Records all changes in a journal shared with other instances.
+    pub fn with_journal(mut self, journal: Journal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Announces a change of the given digest to other instances.
+    pub(crate) async fn record_change(&self, digest: &str) {
+        let Some(journal) = &self.journal else {
+            return;
+        };
+
+        let key = format!(
+            "{}{:020}-{}-{}",
+            JOURNAL_PREFIX,
+            now_micros(),
+            journal.instance_id,
+            digest
+        );
+        journal.seen.lock().insert(key.clone());
+        if let Err(err) = self.backend.put(&key, Bytes::new()).await {
+            warn!("unable to record change of {} in journal: {}", digest, err);
+        }
+    }
+
+    /// Applies all changes made by other instances and removes expired journal entries.
+    /// Returns the number of digests that have been reloaded.
+    pub async fn sync_journal(&self) -> Result<usize> {
+        let Some(journal) = &self.journal else {
+            return Ok(0);
+        };
+
+        let mut keys = self
+            .backend
+            .list_after(JOURNAL_PREFIX)
+            .await
+            .context("Unable to list journal entries")?;
+        keys.retain(|key| key.starts_with(JOURNAL_PREFIX));
+
+        let expired_before = now_micros().saturating_sub(journal.retention.as_micros());
+        let mut reloaded = 0;
+        for key in keys.iter() {
+            let Some(entry) = JournalEntry::parse(key) else {
+                continue;
+            };
+
+            if entry.timestamp < expired_before {
+                // other instances might have removed the entry already
+                self.backend.delete(key).await.ok();
+                continue;
+            }
+
+            if journal.seen.lock().contains(key) {
+                continue;
+            }
+
+            if entry.instance_id != journal.instance_id {
+                info!(
+                    "reloading plugin {} changed by instance {}",
+                    entry.digest, entry.instance_id
+                );
+                if let Err(err) = self.reload(entry.digest).await {
+                    // the entry is not marked as seen and retried on the next sync
+                    warn!("unable to reload plugin {}: {}", entry.digest, err);
+                    continue;
+                }
+                reloaded += 1;
+            }
+            journal.seen.lock().insert(key.to_owned());
+        }
+
+        // forget about entries that have been removed
+        journal.seen.lock().retain(|key| keys.contains(key));
+
+        Ok(reloaded)
+    }
+
+    /// Replaces the database entries of the digest with the current state in the backend.
+    pub(super) async fn reload(&self, digest: &str) -> Result<()> {
+        let metadata = if self.backend.stat(&plugin_key(digest)).await?.is_some() {
+            match self.metadata(digest).await {
+                Ok(metadata) => Some(metadata),
+                Err(Error::NotFound(_)) => None,
+                Err(err) => return Err(err),
+            }
+        } else {
+            None
+        };
+
+        let mut database = self.database.write();
+        database.delete_by_digest(digest)?;
+        if let Some(metadata) = metadata.filter(PluginMetadata::is_visible) {
+            database.insert_all(&metadata)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{
+        backend::{BlobStore, MemoryStore},
+        database::tests::put_plugin,
+        meta_key,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn sync_between_instances() {
+        let backend = Arc::new(MemoryStore::new());
+        let retention = Duration::from_secs(60);
+        let a = Storage::with_backend(backend.clone())
+            .await
+            .unwrap()
+            .with_journal(Journal::new("a", retention).unwrap());
+        let b = Storage::with_backend(backend.clone())
+            .await
+            .unwrap()
+            .with_journal(Journal::new("b", retention).unwrap());
+        let variants = |storage: &Storage| {
+            storage
+                .database()
+                .plugin_variants("coredump", Default::default())
+                .unwrap()
+                .len()
+        };
+
+        // simulate an upload on instance a
+        let digest = put_plugin(&backend, "coredump", "0.2.0").await;
+        a.reload(&digest).await.unwrap();
+        a.record_change(&digest).await;
+
+        assert_eq!(a.sync_journal().await.unwrap(), 0);
+        assert_eq!(variants(&b), 0);
+        assert_eq!(b.sync_journal().await.unwrap(), 1);
+        assert_eq!(variants(&b), 1);
+        assert_eq!(b.sync_journal().await.unwrap(), 0);
+
+        // deletes on instance b are propagated to a
+        b.delete(&digest).await.unwrap();
+        assert_eq!(a.sync_journal().await.unwrap(), 1);
+        assert_eq!(variants(&a), 0);
+
+        // entries that can not be applied do not block the following ones
+        let corrupt = put_plugin(&backend, "coredump", "0.1.0").await;
+        backend
+            .put(&meta_key(&corrupt), Bytes::from_static(b"{\"digest\":"))
+            .await
+            .unwrap();
+        backend
+            .put(
+                &format!("{}{:020}-c-{}", JOURNAL_PREFIX, now_micros(), corrupt),
+                Bytes::new(),
+            )
+            .await
+            .unwrap();
+        let digest = put_plugin(&backend, "coredump", "0.3.0").await;
+        b.record_change(&digest).await;
+        assert_eq!(a.sync_journal().await.unwrap(), 1);
+        assert_eq!(variants(&a), 1);
+    }
+
+    #[test]
+    fn parse_entry() {
+        let entry = JournalEntry::parse("~journal-00000000000000000042-abc-aaaa").unwrap();
+        assert_eq!(entry.timestamp, 42);
+        assert_eq!(entry.instance_id, "abc");
+        assert_eq!(entry.digest, "aaaa");
+        assert!(JournalEntry::parse("aaaa.meta").is_none());
+    }
+}
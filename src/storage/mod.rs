@@ -1,44 +1,96 @@
-use std::path::Path;
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 use chrono::{NaiveDateTime, Utc};
-use log::warn;
+use log::{info, warn};
 use memflow::plugins::plugin_analyzer;
 use memflow::plugins::plugin_analyzer::PluginDescriptorInfo;
 use parking_lot::{lock_api::RwLockReadGuard, RawRwLock, RwLock};
 use serde::{Deserialize, Serialize};
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use utoipa::ToSchema;
 
 use crate::error::ResultExt;
 use crate::{
     error::{Error, Result},
-    pki::SignatureVerifier,
+    pki::{Keyring, SignatureScheme},
 };
 
+pub mod backend;
 pub mod database;
-use database::PluginDatabase;
+mod health;
+pub mod journal;
+mod recovery;
+pub mod spool;
+mod tags;
+mod trash;
+pub mod upstream;
+mod verify;
+use backend::{BlobInfo, BlobStore, BlobStream, FileSystemStore};
+use database::{MemoryDatabase, PluginDatabase};
+use health::IndexCheck;
+pub use health::{
+    CheckStatus, HealthCheck, ReadinessReport, DEFAULT_MIN_FREE_SPACE, INDEX_CHECK_INTERVAL,
+};
+use journal::Journal;
+pub use recovery::{RecoveryMode, RecoveryReport};
+use spool::{PluginContents, SpooledFile, DEFAULT_MAX_UPLOAD_SIZE};
+pub use tags::{is_valid_tag, PluginTags, TagEntry};
+use upstream::Upstream;
+pub use verify::VerifyReport;
 
 /// Metadata attached to each file
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct PluginMetadata {
     /// The sha256sum of the binary file
     pub digest: String,
     /// File signature of this binary
     pub signature: String,
+    /// Scheme of the signature, files uploaded before ed25519 support are always ecdsa
+    #[serde(default)]
+    pub signature_scheme: SignatureScheme,
+    /// Id of the trusted key which created the signature
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
     /// Timestamp at which the file was added
     pub created_at: NaiveDateTime,
     /// The plugin descriptor
+    #[schema(value_type = Vec<crate::rest::models::PluginDescriptorInfoSchema>)]
     pub descriptors: Vec<PluginDescriptorInfo>,
+    /// Timestamp at which the file was quarantined because its signature could not be verified anymore
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quarantined_at: Option<NaiveDateTime>,
+    /// Timestamp at which the file was moved to the trash
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<NaiveDateTime>,
+    /// Timestamp at which the file was yanked, yanked files are still served by their digest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yanked_at: Option<NaiveDateTime>,
 }
 
-/// Local Plugin storage
+impl PluginMetadata {
+    /// Returns true if the file is neither quarantined nor in the trash and should be part of the database.
+    #[inline]
+    pub fn is_visible(&self) -> bool {
+        self.quarantined_at.is_none() && self.deleted_at.is_none()
+    }
+}
+
+/// Plugin storage
 #[derive(Clone)]
 pub struct Storage {
-    root: PathBuf,
-    database: Arc<RwLock<PluginDatabase>>,
-    signature_verifier: Option<SignatureVerifier>,
+    backend: Arc<dyn BlobStore>,
+    database: Arc<RwLock<Box<dyn PluginDatabase>>>,
+    keyring: Option<Keyring>,
+    upstream: Option<Upstream>,
+    journal: Option<Journal>,
+    max_upload_size: u64,
+    upload_dir: PathBuf,
+    trash_retention: Option<Duration>,
+    tags_lock: Arc<tokio::sync::Mutex<()>>,
+    min_free_space: u64,
+    index_check: Arc<RwLock<IndexCheck>>,
 }
 
 /// Result of an upload request
@@ -49,141 +101,472 @@ pub enum UploadResponse {
 }
 
 impl Storage {
-    pub fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
-        // TODO: create path if not exists
-        let mut database = PluginDatabase::new();
-
-        let paths = std::fs::read_dir(&root).context(&format!(
-            "Unable to read database directory '{:?}'",
-            root.as_ref()
-        ))?;
-        for path in paths.filter_map(|p| p.ok()) {
-            if let Some(extension) = path.path().extension() {
-                if extension.to_str().unwrap_or_default() == "meta" {
-                    let contents = std::fs::read_to_string(path.path())
-                        .context(&format!("Unable to read {:?}", path.path()))?;
-                    let metadata: PluginMetadata = serde_json::from_str(&contents).context(
-                        &format!("Unable to deserialize metadata file {:?}", path.path()),
-                    )?;
-                    database
-                        .insert_all(&metadata)
-                        .context("Unable to add plugin to database")?;
+    /// Creates a new storage which keeps all files in the given local directory.
+    pub async fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
+        Self::with_backend(FileSystemStore::new(root)?).await
+    }
+
+    /// Creates a new storage on top of the given backend with an in-memory database
+    /// that is populated from all metadata files found in the backend.
+    pub async fn with_backend<B: BlobStore + 'static>(backend: B) -> Result<Self> {
+        Self::with_database(backend, MemoryDatabase::new()).await
+    }
+
+    /// Creates a new storage on top of the given backend and database.
+    /// In case the database is empty it will be populated from all metadata files found in the backend,
+    /// otherwise it is reconciled with the files that have been added or removed in the meantime.
+    pub async fn with_database<B: BlobStore + 'static, D: PluginDatabase + 'static>(
+        backend: B,
+        database: D,
+    ) -> Result<Self> {
+        let storage = Self {
+            backend: Arc::new(backend),
+            database: Arc::new(RwLock::new(Box::new(database))),
+            keyring: None,
+            upstream: None,
+            journal: None,
+            max_upload_size: DEFAULT_MAX_UPLOAD_SIZE,
+            upload_dir: std::env::temp_dir(),
+            trash_retention: None,
+            tags_lock: Default::default(),
+            min_free_space: DEFAULT_MIN_FREE_SPACE,
+            index_check: Default::default(),
+        };
+
+        if storage.database.read().is_empty()? {
+            storage.rebuild_database().await?;
+        } else {
+            storage.reconcile_database().await?;
+        }
+
+        Ok(storage)
+    }
+
+    /// Clears the database and re-adds all metadata files found in the backend.
+    /// Returns the number of metadata files that have been added.
+    pub async fn rebuild_database(&self) -> Result<usize> {
+        let keys = self
+            .backend
+            .list()
+            .await
+            .context("Unable to list storage contents")?;
+
+        let mut metadata = Vec::new();
+        for key in keys.iter().filter(|key| key.ends_with(".meta")) {
+            let contents = self
+                .backend
+                .read(key)
+                .await
+                .context(&format!("Unable to read {:?}", key))?;
+            // corrupt metadata files are skipped and handled by the recovery pass
+            match serde_json::from_slice::<PluginMetadata>(&contents) {
+                Ok(entry) => metadata.push(entry),
+                Err(err) => warn!("unable to deserialize metadata file {:?}: {}", key, err),
+            }
+        }
+
+        // quarantined and deleted files are kept in the storage but hidden from the database
+        metadata.retain(PluginMetadata::is_visible);
+
+        let mut database = self.database.write();
+        database.clear()?;
+        for metadata in metadata.iter() {
+            database
+                .insert_all(metadata)
+                .context("Unable to add plugin to database")?;
+        }
+
+        Ok(metadata.len())
+    }
+
+    /// Adds and removes all files whose presence in the storage does not match the database,
+    /// e.g. because they have been changed by another instance while this one was not running.
+    ///
+    /// Returns the number of files that have been added or removed.
+    pub async fn reconcile_database(&self) -> Result<usize> {
+        let keys = self
+            .backend
+            .list()
+            .await
+            .context("Unable to list storage contents")?;
+        let plugins = keys
+            .iter()
+            .filter_map(|key| key.strip_suffix(".plugin"))
+            .collect::<HashSet<_>>();
+        let stored = keys
+            .iter()
+            .filter_map(|key| key.strip_suffix(".meta"))
+            .filter(|digest| plugins.contains(digest))
+            .collect::<HashSet<_>>();
+        let indexed = self
+            .database
+            .read()
+            .digests()?
+            .into_iter()
+            .collect::<HashSet<_>>();
+
+        let mut reloaded = 0;
+        for digest in stored.iter().filter(|digest| !indexed.contains(**digest)) {
+            // corrupt metadata files are skipped and handled by the recovery pass
+            match self.metadata(digest).await {
+                // quarantined and deleted files are expected to be missing from the database
+                Ok(metadata) if metadata.is_visible() => {
+                    self.database.write().insert_all(&metadata)?;
+                    reloaded += 1;
                 }
+                Ok(_) => (),
+                Err(err) => warn!("unable to reload plugin {}: {}", digest, err),
             }
         }
+        for digest in indexed
+            .iter()
+            .filter(|digest| !stored.contains(digest.as_str()))
+        {
+            self.database.write().delete_by_digest(digest)?;
+            reloaded += 1;
+        }
+
+        if reloaded > 0 {
+            info!("reconciled {} files with the plugin database", reloaded);
+        }
+        Ok(reloaded)
+    }
 
-        Ok(Self {
-            root: root.as_ref().to_path_buf(),
-            database: Arc::new(RwLock::new(database)),
-            signature_verifier: None,
-        })
+    /// Adds the given Keyring to the file store.
+    /// Uploaded files have to be signed by one of the keys in the keyring.
+    pub fn with_keyring(mut self, keyring: Keyring) -> Self {
+        self.keyring = Some(keyring);
+        self
+    }
+
+    /// Sets the maximum size of uploaded files.
+    pub fn with_max_upload_size(mut self, max_upload_size: u64) -> Self {
+        self.max_upload_size = max_upload_size;
+        self
     }
 
-    /// Adds the given SignatureVerifier to the file store.
-    pub fn with_signature_verifier(mut self, verifier: SignatureVerifier) -> Self {
-        self.signature_verifier = Some(verifier);
+    /// Sets the directory in which uploads are stored temporarily.
+    pub fn with_upload_dir<P: AsRef<Path>>(mut self, upload_dir: P) -> Self {
+        self.upload_dir = upload_dir.as_ref().to_path_buf();
         self
     }
 
+    /// Returns the maximum size of uploaded files.
+    #[inline]
+    pub fn max_upload_size(&self) -> u64 {
+        self.max_upload_size
+    }
+
+    /// Creates a temporary file for an upload that is passed to [`Storage::upload_spooled`].
+    pub fn spool(&self) -> Result<SpooledFile> {
+        SpooledFile::new(&self.upload_dir, self.max_upload_size)
+    }
+
     /// Writes the specified connector into the path and adds it into the database.
     pub async fn upload(&self, bytes: &[u8], signature: &str) -> Result<UploadResponse> {
-        // TODO: what happens with old signatures in case we change the signing key?
-        if let Some(verifier) = &self.signature_verifier {
-            if let Err(err) = verifier.is_valid(bytes, signature) {
-                warn!("invalid file signature for uploaded binary: {}", err);
-                return Err(Error::Signature("file signature is invalid".to_owned()));
-            }
+        let key_id = match &self.keyring {
+            Some(keyring) => match keyring.verify(bytes, signature) {
+                Ok(key_id) => Some(key_id.to_owned()),
+                Err(err) => {
+                    warn!("invalid file signature for uploaded binary: {}", err);
+                    return Err(Error::Signature("file signature is invalid".to_owned()));
+                }
+            },
+            None => None,
+        };
+
+        self.store(
+            PluginContents::Memory(bytes),
+            signature,
+            key_id,
+            Utc::now().naive_utc(),
+        )
+        .await
+    }
+
+    /// Writes the uploaded temporary file into the storage and adds it into the database.
+    pub async fn upload_spooled(
+        &self,
+        mut file: SpooledFile,
+        signature: &str,
+    ) -> Result<UploadResponse> {
+        file.finish().await?;
+
+        let key_id = match &self.keyring {
+            Some(keyring) => match self.verify_spooled(keyring, &file, signature) {
+                Ok(key_id) => Some(key_id.to_owned()),
+                Err(err) => {
+                    warn!("invalid file signature for uploaded binary: {}", err);
+                    return Err(Error::Signature("file signature is invalid".to_owned()));
+                }
+            },
+            None => None,
+        };
+
+        self.store(
+            PluginContents::Spooled(&file),
+            signature,
+            key_id,
+            Utc::now().naive_utc(),
+        )
+        .await
+    }
+
+    /// Verifies the signature of the uploaded file against the keyring.
+    /// The file is only mapped into memory if one of the keys can not verify digests.
+    fn verify_spooled<'a>(
+        &self,
+        keyring: &'a Keyring,
+        file: &SpooledFile,
+        signature: &str,
+    ) -> Result<&'a str> {
+        if keyring.supports_digest() {
+            keyring.verify_digest(file.hasher(), signature)
+        } else {
+            keyring.verify(&file.map()?[..], signature)
         }
+    }
 
-        // parse descriptors
-        let descriptors = plugin_analyzer::parse_descriptors(bytes)?;
+    /// Returns the scheme of the signature created by the given key.
+    ///
+    /// Signatures of unknown keys are not verified, their scheme is guessed from the signature.
+    fn signature_scheme(&self, key_id: Option<&str>, signature: &str) -> SignatureScheme {
+        key_id
+            .zip(self.keyring.as_ref())
+            .and_then(|(key_id, keyring)| keyring.scheme(key_id))
+            .unwrap_or_else(|| SignatureScheme::guess(signature))
+    }
 
+    /// Writes a file with an already verified signature into the storage and adds it into the database.
+    async fn store(
+        &self,
+        contents: PluginContents<'_>,
+        signature: &str,
+        key_id: Option<String>,
+        created_at: NaiveDateTime,
+    ) -> Result<UploadResponse> {
         // generate sha256 digest
-        let digest = sha256::digest(bytes);
-
-        // plugin path: {digest}.plugin
-        let mut file_name = self.root.clone().join(&digest);
-        file_name.set_extension("plugin");
+        let digest = contents.digest();
 
         // check if digest is already existent
-        if file_name.exists() {
-            warn!("plugin with the same digest was already added");
-            return Ok(UploadResponse::AlreadyExists);
+        let existing = if self.backend.stat(&plugin_key(&digest)).await?.is_some() {
+            match self.metadata(&digest).await {
+                Ok(metadata) => Some(metadata),
+                // the previous upload was interrupted before the metadata was written, replace it
+                Err(err @ (Error::NotFound(_) | Error::Parse(_))) => {
+                    warn!("replacing plugin {} with missing metadata: {}", digest, err);
+                    None
+                }
+                Err(err) => return Err(err),
+            }
+        } else {
+            None
+        };
+        if let Some(mut metadata) = existing {
+            let restore_deleted = metadata.deleted_at.is_some();
+            let restore_quarantined = metadata.quarantined_at.is_some() && key_id.is_some();
+            if !restore_deleted && !restore_quarantined {
+                warn!("plugin with the same digest was already added");
+                return Ok(UploadResponse::AlreadyExists);
+            }
+
+            if restore_deleted {
+                // uploading a deleted file again restores it from the trash
+                info!("restoring deleted plugin {}", digest);
+                metadata.deleted_at = None;
+            }
+            if restore_quarantined {
+                // a quarantined file has been re-signed by a trusted key
+                info!(
+                    "restoring quarantined plugin {} signed by key `{}`",
+                    digest,
+                    key_id.as_deref().unwrap_or_default()
+                );
+                metadata.signature_scheme = self.signature_scheme(key_id.as_deref(), signature);
+                metadata.signature = signature.to_owned();
+                metadata.key_id = key_id;
+                metadata.quarantined_at = None;
+            }
+            // the stored contents might have been quarantined because they did not match the digest
+            contents
+                .put(self.backend.as_ref(), &plugin_key(&digest))
+                .await?;
+            self.write_metadata(&metadata).await?;
+            if metadata.is_visible() {
+                self.database.write().insert_all(&metadata)?;
+            }
+            self.record_change(&digest).await;
+            return Ok(UploadResponse::Added);
         }
 
+        // parse descriptors
+        let descriptors =
+            contents.with_bytes(|bytes| Ok(plugin_analyzer::parse_descriptors(bytes)?))?;
+
         // write plugin
-        let mut plugin_file = File::create(&file_name).await?;
-        plugin_file.write_all(bytes).await?;
+        contents
+            .put(self.backend.as_ref(), &plugin_key(&digest))
+            .await?;
 
-        // metadata path: {digest}.meta
+        // write metadata
         let metadata = PluginMetadata {
             digest: digest.clone(),
             signature: signature.to_owned(),
-            created_at: Utc::now().naive_utc(),
+            signature_scheme: self.signature_scheme(key_id.as_deref(), signature),
+            key_id,
+            created_at,
             descriptors: descriptors.clone(),
+            quarantined_at: None,
+            deleted_at: None,
+            yanked_at: None,
         };
-        file_name.set_extension("meta");
-        let mut metadata_file = File::create(&file_name).await?;
-        metadata_file
-            .write_all(serde_json::to_string(&metadata).unwrap().as_bytes())
-            .await?;
+        self.write_metadata(&metadata).await?;
 
         // add to database
-        let mut database = self.database.write();
-        database.insert_all(&metadata)?;
+        self.database.write().insert_all(&metadata)?;
+        self.record_change(&digest).await;
 
         Ok(UploadResponse::Added)
     }
 
-    /// Returns a handle to the file
-    pub async fn download(&self, digest: &str) -> Result<File> {
-        let mut file_name = self.root.clone().join(digest);
-        file_name.set_extension("plugin");
-        Ok(File::open(&file_name).await?)
+    /// Returns the size and a stream of the file contents.
+    ///
+    /// In pull-through mode missing files are fetched from the upstream registry first.
+    pub async fn download(&self, digest: &str) -> Result<(BlobInfo, BlobStream)> {
+        let key = plugin_key(digest);
+        let info = match self.backend.stat(&key).await? {
+            Some(info) => info,
+            None if self.upstream.is_some() => {
+                self.mirror(digest).await?;
+                self.backend
+                    .stat(&key)
+                    .await?
+                    .ok_or_else(|| Error::NotFound("digest was not found".to_owned()))?
+            }
+            None => return Err(Error::NotFound("digest was not found".to_owned())),
+        };
+
+        // files in the trash and files which are not trusted anymore are not served
+        if let Ok(metadata) = self.metadata(digest).await {
+            if metadata.deleted_at.is_some() {
+                return Err(Error::NotFound("digest was not found".to_owned()));
+            }
+            if metadata.quarantined_at.is_some() {
+                return Err(Error::NotFound("digest is quarantined".to_owned()));
+            }
+        }
+
+        let stream = self.backend.get(&key).await?;
+        Ok((info, stream))
     }
 
     /// Returns the metadata of the file
     pub async fn metadata(&self, digest: &str) -> Result<PluginMetadata> {
-        let mut file_name = self.root.clone().join(digest);
-        file_name.set_extension("meta");
-        let content = tokio::fs::read_to_string(&file_name).await?;
-        Ok(serde_json::from_str(&content).unwrap())
+        let content = self.backend.read(&meta_key(digest)).await?;
+        Ok(serde_json::from_slice(&content)?)
     }
 
-    /// Deletes the file with the given digest from the database.
+    /// Writes the metadata file for the digest of the metadata.
+    async fn write_metadata(&self, metadata: &PluginMetadata) -> Result<()> {
+        self.backend
+            .put(
+                &meta_key(&metadata.digest),
+                serde_json::to_vec(metadata)?.into(),
+            )
+            .await
+    }
+
+    /// Deletes the file with the given digest and its metadata.
+    ///
+    /// In case the trash is enabled the file is only hidden and can be restored until the retention expired.
+    /// Deleting a file that already is in the trash removes it permanently.
     pub async fn delete(&self, digest: &str) -> Result<()> {
         // check if file exists
-        let mut file_name = self.root.clone().join(digest);
-        file_name.set_extension("plugin");
-        if !file_name.exists() {
+        let key = plugin_key(digest);
+        if self.backend.stat(&key).await?.is_none() {
             return Err(Error::NotFound("digest was not found".to_owned()));
         }
 
         // lock and remove from database
         {
             let mut database = self.database.write();
-            database.delete_by_digest(digest);
+            database.delete_by_digest(digest)?;
+        }
+
+        if self.trash_retention.is_some() {
+            if let Ok(mut metadata) = self.metadata(digest).await {
+                if metadata.deleted_at.is_none() {
+                    info!("moving plugin {} to the trash", digest);
+                    metadata.deleted_at = Some(Utc::now().naive_utc());
+                    self.write_metadata(&metadata).await?;
+                    self.record_change(digest).await;
+                    return Ok(());
+                }
+            }
         }
 
-        // try to remove the file
-        tokio::fs::remove_file(file_name).await?;
+        // remove the metadata first so the file is not added to the database again after a crash
+        match self.backend.delete(&meta_key(digest)).await {
+            Ok(()) | Err(Error::NotFound(_)) => (),
+            Err(err) => return Err(err),
+        }
+        self.backend.delete(&key).await?;
+        self.record_change(digest).await;
 
         Ok(())
     }
 
-    /// Returns the health state of the database by checking if the storage folder is still accessible
-    #[inline]
-    pub fn health(&self) -> Result<()> {
-        let paths = std::fs::read_dir(&self.root)?;
-        for _path in paths.filter_map(|p| p.ok()) {
-            // no-op
+    /// Marks the file as yanked or removes the mark.
+    /// Yanked files are hidden from plugin queries but can still be downloaded by their digest.
+    pub async fn set_yanked(&self, digest: &str, yanked: bool) -> Result<()> {
+        let mut metadata = self.metadata(digest).await?;
+        if metadata.yanked_at.is_some() == yanked {
+            return Ok(());
+        }
+
+        info!(
+            "{} plugin {}",
+            if yanked { "yanking" } else { "unyanking" },
+            digest
+        );
+        metadata.yanked_at = yanked.then(|| Utc::now().naive_utc());
+        self.write_metadata(&metadata).await?;
+        if metadata.is_visible() {
+            let mut database = self.database.write();
+            database.delete_by_digest(digest)?;
+            database.insert_all(&metadata)?;
         }
+        self.record_change(digest).await;
+
         Ok(())
     }
 
+    /// Returns the health state of the database by checking if the storage backend is still accessible
+    #[inline]
+    pub async fn health(&self) -> Result<()> {
+        self.backend.health().await
+    }
+
+    /// Returns the total size of all stored plugin files and metadata in bytes.
+    pub async fn disk_usage(&self) -> Result<u64> {
+        self.backend.usage().await
+    }
+
     /// Returns a read-only lock to the underlying database
     #[inline]
-    pub fn database(&self) -> RwLockReadGuard<RawRwLock, PluginDatabase> {
+    pub fn database(&self) -> RwLockReadGuard<'_, RawRwLock, Box<dyn PluginDatabase>> {
         self.database.read()
     }
 }
+
+/// Storage key of the plugin binary: `{digest}.plugin`
+#[inline]
+fn plugin_key(digest: &str) -> String {
+    format!("{}.plugin", digest)
+}
+
+/// Storage key of the plugin metadata: `{digest}.meta`
+#[inline]
+fn meta_key(digest: &str) -> String {
+    format!("{}.meta", digest)
+}
@@ -0,0 +1,337 @@
+//! Recovery pass which repairs the storage after a crash or an interrupted write
+
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::Utc;
+use futures_util::StreamExt;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result, ResultExt};
+use crate::pki::encode_hex;
+
+use super::{meta_key, plugin_key, Storage};
+
+/// Suffix appended to the keys of blobs that have been moved aside
+const CORRUPT_SUFFIX: &str = ".corrupt";
+
+/// Plugins without metadata which are younger than this might still be uploaded by another instance
+const ORPHAN_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// Checks performed by the recovery pass
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryMode {
+    /// Skips the recovery pass
+    Off,
+    /// Checks that every plugin has valid metadata and vice versa
+    Quick,
+    /// Additionally checks the digest of every plugin, this reads all files in the storage
+    Full,
+}
+
+impl FromStr for RecoveryMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(Self::Off),
+            "quick" => Ok(Self::Quick),
+            "full" => Ok(Self::Full),
+            _ => Err(Error::Parse(format!("unknown recovery mode `{}`", s))),
+        }
+    }
+}
+
+/// Changes made by a recovery pass
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RecoveryReport {
+    /// Number of plugins that have been checked
+    pub checked: usize,
+    /// Plugins without metadata which have been moved aside
+    pub orphaned: Vec<String>,
+    /// Metadata files without a plugin which have been moved aside
+    pub stale: Vec<String>,
+    /// Plugins with unreadable metadata which have been moved aside together with their metadata
+    pub corrupt: Vec<String>,
+    /// Plugins whose contents do not match their digest and which have been quarantined
+    pub mismatched: Vec<String>,
+}
+
+impl RecoveryReport {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.orphaned.is_empty()
+            && self.stale.is_empty()
+            && self.corrupt.is_empty()
+            && self.mismatched.is_empty()
+    }
+}
+
+impl Storage {
+    /// Detects and repairs inconsistencies left behind by a crash.
+    ///
+    /// Blobs that cannot be repaired are moved aside to `{key}.corrupt` and removed from the database.
+    /// Plugins whose contents do not match their digest are quarantined.
+    pub async fn recover(&self, mode: RecoveryMode) -> Result<RecoveryReport> {
+        self.recover_with_grace_period(mode, ORPHAN_GRACE_PERIOD)
+            .await
+    }
+
+    async fn recover_with_grace_period(
+        &self,
+        mode: RecoveryMode,
+        grace_period: Duration,
+    ) -> Result<RecoveryReport> {
+        let mut report = RecoveryReport::default();
+        if mode == RecoveryMode::Off {
+            return Ok(report);
+        }
+
+        let keys = self
+            .backend
+            .list()
+            .await
+            .context("Unable to list storage contents")?;
+        let plugins = keys
+            .iter()
+            .filter_map(|key| key.strip_suffix(".plugin"))
+            .collect::<HashSet<_>>();
+        let metas = keys
+            .iter()
+            .filter_map(|key| key.strip_suffix(".meta"))
+            .collect::<HashSet<_>>();
+
+        // metadata of files that no longer exist
+        for digest in metas.difference(&plugins) {
+            warn!("moving aside metadata of missing plugin {}", digest);
+            self.move_aside(&meta_key(digest)).await?;
+            self.database.write().delete_by_digest(digest)?;
+            report.stale.push(digest.to_string());
+        }
+
+        for digest in plugins.iter() {
+            report.checked += 1;
+
+            // uploads that have been interrupted before the metadata was written
+            if !metas.contains(digest) {
+                // other instances sharing the backend might still be writing the metadata
+                let age = self
+                    .backend
+                    .stat(&plugin_key(digest))
+                    .await?
+                    .and_then(|info| info.modified)
+                    .and_then(|modified| modified.elapsed().ok());
+                if !age.is_some_and(|age| age > grace_period) {
+                    info!("skipping recent plugin {} without metadata", digest);
+                    continue;
+                }
+
+                warn!("moving aside plugin {} without metadata", digest);
+                self.move_aside(&plugin_key(digest)).await?;
+                report.orphaned.push(digest.to_string());
+                continue;
+            }
+
+            let mut metadata = match self.metadata(digest).await {
+                Ok(metadata) if metadata.digest == *digest => metadata,
+                Ok(_) | Err(Error::Parse(_)) => {
+                    warn!("moving aside plugin {} with corrupt metadata", digest);
+                    self.move_aside(&meta_key(digest)).await?;
+                    self.move_aside(&plugin_key(digest)).await?;
+                    self.database.write().delete_by_digest(digest)?;
+                    report.corrupt.push(digest.to_string());
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+
+            if mode == RecoveryMode::Full
+                && metadata.quarantined_at.is_none()
+                && self.plugin_digest(digest).await? != *digest
+            {
+                warn!(
+                    "contents of plugin {} do not match, quarantining it",
+                    digest
+                );
+                metadata.quarantined_at = Some(Utc::now().naive_utc());
+                self.write_metadata(&metadata).await?;
+                self.database.write().delete_by_digest(digest)?;
+                report.mismatched.push(digest.to_string());
+            }
+        }
+
+        for digest in report
+            .stale
+            .iter()
+            .chain(report.corrupt.iter())
+            .chain(report.mismatched.iter())
+        {
+            self.record_change(digest).await;
+        }
+
+        // every indexed file is still part of the listed keys after the repairs above
+        self.update_index_check(&keys);
+
+        info!(
+            "recovered storage with {} plugins: {} orphaned, {} stale, {} corrupt, {} mismatched",
+            report.checked,
+            report.orphaned.len(),
+            report.stale.len(),
+            report.corrupt.len(),
+            report.mismatched.len()
+        );
+        Ok(report)
+    }
+
+    /// Computes the sha256 digest of the stored plugin without loading it into memory.
+    async fn plugin_digest(&self, digest: &str) -> Result<String> {
+        let mut stream = self.backend.get(&plugin_key(digest)).await?;
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = stream.next().await {
+            hasher.update(&chunk?);
+        }
+        Ok(encode_hex(&hasher.finalize()))
+    }
+
+    /// Moves the blob to `{key}.corrupt` so it is not picked up anymore but can still be inspected.
+    async fn move_aside(&self, key: &str) -> Result<()> {
+        self.backend
+            .rename(key, &format!("{}{}", key, CORRUPT_SUFFIX))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::pki::{tests::key_pair, Keyring, SignatureVerifier};
+    use crate::storage::{
+        backend::{BlobStore, MemoryStore},
+        database::tests::{metadata, plugin_contents, put_plugin, put_signed_plugin},
+        UploadResponse,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn recover() {
+        let backend = std::sync::Arc::new(MemoryStore::new());
+
+        // valid plugin
+        let valid = put_plugin(&backend, "coredump", "0.2.0").await;
+
+        // plugin without metadata
+        let orphaned = sha256::digest("orphaned");
+        backend
+            .put(&plugin_key(&orphaned), Bytes::from_static(b"orphaned"))
+            .await
+            .unwrap();
+
+        // plugin with truncated metadata
+        let corrupt = put_plugin(&backend, "coredump", "0.1.0").await;
+        backend
+            .put(&meta_key(&corrupt), Bytes::from_static(b"{\"digest\":"))
+            .await
+            .unwrap();
+
+        // metadata without plugin
+        let stale = metadata("aaaa", "coredump", "0.1.0", 1, 2);
+        backend
+            .put(
+                &meta_key("aaaa"),
+                serde_json::to_vec(&stale).unwrap().into(),
+            )
+            .await
+            .unwrap();
+
+        // plugin with modified contents
+        let mismatched = put_plugin(&backend, "coredump", "0.3.0").await;
+        backend
+            .put(&plugin_key(&mismatched), Bytes::from_static(b"modified"))
+            .await
+            .unwrap();
+
+        let storage = Storage::with_backend(backend.clone()).await.unwrap();
+
+        let report = storage.recover(RecoveryMode::Full).await.unwrap();
+        assert_eq!(report.checked, 4);
+        assert_eq!(report.stale, vec!["aaaa"]);
+        assert_eq!(report.corrupt, vec![corrupt]);
+        assert_eq!(report.mismatched, vec![mismatched.clone()]);
+
+        // recent plugins without metadata might still be uploaded by another instance
+        assert!(report.orphaned.is_empty());
+        let report = storage
+            .recover_with_grace_period(RecoveryMode::Quick, Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(report.checked, 3);
+        assert_eq!(report.orphaned, vec![orphaned.clone()]);
+
+        // only the valid plugin remains visible
+        let variants = storage
+            .database()
+            .plugin_variants("coredump", Default::default())
+            .unwrap();
+        assert_eq!(variants.len(), 1);
+        assert_eq!(variants[0].digest, valid);
+        assert!(storage
+            .metadata(&mismatched)
+            .await
+            .unwrap()
+            .quarantined_at
+            .is_some());
+        assert!(backend
+            .stat(&format!("{}.plugin.corrupt", orphaned))
+            .await
+            .unwrap()
+            .is_some());
+
+        // a second pass does not find anything
+        let report = storage.recover(RecoveryMode::Full).await.unwrap();
+        assert!(report.is_empty());
+        assert_eq!(report.checked, 2);
+    }
+
+    #[tokio::test]
+    async fn reupload_mismatched() {
+        let (mut alice, alice_pem) = key_pair(1);
+        let mut keyring = Keyring::new();
+        keyring
+            .insert("alice", SignatureVerifier::with_str(&alice_pem).unwrap())
+            .unwrap();
+
+        let backend = std::sync::Arc::new(MemoryStore::new());
+        let digest = put_signed_plugin(&backend, "coredump", "0.2.0", &mut alice).await;
+        backend
+            .put(&plugin_key(&digest), Bytes::from_static(b"modified"))
+            .await
+            .unwrap();
+
+        let storage = Storage::with_backend(backend.clone())
+            .await
+            .unwrap()
+            .with_keyring(keyring);
+        let report = storage.recover(RecoveryMode::Full).await.unwrap();
+        assert_eq!(report.mismatched, vec![digest.clone()]);
+
+        // a trusted upload of the original file replaces the modified contents
+        let contents = plugin_contents("coredump", "0.2.0");
+        let signature = alice.sign(&contents).unwrap();
+        assert!(matches!(
+            storage.upload(&contents, &signature).await.unwrap(),
+            UploadResponse::Added
+        ));
+        let (_, stream) = storage.download(&digest).await.unwrap();
+        let downloaded = stream
+            .map(|chunk| chunk.unwrap())
+            .collect::<Vec<_>>()
+            .await
+            .concat();
+        assert_eq!(downloaded, contents);
+    }
+}
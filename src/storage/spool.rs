@@ -0,0 +1,156 @@
+//! Temporary files for uploads which are too large to be kept in memory
+
+use std::path::Path;
+
+use bytes::Bytes;
+use memmap2::Mmap;
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    error::{Error, Result, ResultExt},
+    pki::encode_hex,
+};
+
+use super::backend::BlobStore;
+
+/// Default maximum size of an uploaded file
+pub const DEFAULT_MAX_UPLOAD_SIZE: u64 = 20 * 1024 * 1024; // 20 mb
+
+/// An uploaded file which is written to a temporary file while it is being received.
+/// The sha256 digest is computed incrementally.
+pub struct SpooledFile {
+    temp: NamedTempFile,
+    file: tokio::fs::File,
+    hasher: Sha256,
+    size: u64,
+    max_size: u64,
+}
+
+impl SpooledFile {
+    /// Creates a new temporary file in the given directory.
+    pub fn new<P: AsRef<Path>>(dir: P, max_size: u64) -> Result<Self> {
+        let temp = NamedTempFile::new_in(dir.as_ref()).context(&format!(
+            "Unable to create temporary file in {:?}",
+            dir.as_ref()
+        ))?;
+        let file = tokio::fs::File::from_std(temp.reopen()?);
+        Ok(Self {
+            temp,
+            file,
+            hasher: Sha256::new(),
+            size: 0,
+            max_size,
+        })
+    }
+
+    /// Appends the chunk to the file.
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<()> {
+        self.size += chunk.len() as u64;
+        if self.size > self.max_size {
+            return Err(Error::TooLarge(format!(
+                "file exceeds the maximum size of {} bytes",
+                self.max_size
+            )));
+        }
+
+        self.hasher.update(chunk);
+        self.file.write_all(chunk).await?;
+        Ok(())
+    }
+
+    /// Flushes all written data to the disk.
+    pub async fn finish(&mut self) -> Result<()> {
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+        Ok(())
+    }
+
+    /// Returns the number of bytes written.
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the hex encoded sha256 digest of all bytes written.
+    pub fn digest(&self) -> String {
+        encode_hex(&self.hasher.clone().finalize())
+    }
+
+    /// Returns the hasher containing all bytes written, used for signature verification.
+    #[inline]
+    pub fn hasher(&self) -> &Sha256 {
+        &self.hasher
+    }
+
+    #[inline]
+    pub fn path(&self) -> &Path {
+        self.temp.path()
+    }
+
+    /// Maps the file into memory.
+    pub fn map(&self) -> Result<Mmap> {
+        // SAFETY: the temporary file is owned by this struct and is not modified while mapped
+        Ok(unsafe { Mmap::map(self.temp.as_file())? })
+    }
+}
+
+/// Contents of a plugin that is about to be stored
+pub(crate) enum PluginContents<'a> {
+    Memory(&'a [u8]),
+    Spooled(&'a SpooledFile),
+}
+
+impl PluginContents<'_> {
+    /// Calls the function with the entire contents.
+    pub fn with_bytes<T>(&self, f: impl FnOnce(&[u8]) -> Result<T>) -> Result<T> {
+        match self {
+            PluginContents::Memory(bytes) => f(bytes),
+            PluginContents::Spooled(file) => f(&file.map()?[..]),
+        }
+    }
+
+    pub fn digest(&self) -> String {
+        match self {
+            PluginContents::Memory(bytes) => sha256::digest(*bytes),
+            PluginContents::Spooled(file) => file.digest(),
+        }
+    }
+
+    /// Writes the contents into the backend.
+    pub async fn put(&self, backend: &dyn BlobStore, key: &str) -> Result<()> {
+        match self {
+            PluginContents::Memory(bytes) => backend.put(key, Bytes::copy_from_slice(bytes)).await,
+            PluginContents::Spooled(file) => backend.put_file(key, file.path()).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn spool() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file = SpooledFile::new(dir.path(), 10).unwrap();
+        file.write(b"plug").await.unwrap();
+        file.write(b"in").await.unwrap();
+        file.finish().await.unwrap();
+
+        assert_eq!(file.size(), 6);
+        assert_eq!(file.digest(), sha256::digest("plugin"));
+        assert_eq!(&file.map().unwrap()[..], b"plugin");
+
+        assert!(matches!(
+            file.write(b"too large").await,
+            Err(Error::TooLarge(_))
+        ));
+
+        // the file is removed once dropped
+        let path = file.path().to_path_buf();
+        drop(file);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,333 @@
+//! Mutable tags like `stable` or `nightly` which point to specific files of a plugin
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use chrono::{NaiveDateTime, Utc};
+use log::{info, warn};
+use memflow::plugins::plugin_analyzer::{PluginArchitecture, PluginFileType};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::error::{Error, Result};
+
+use super::{backend::BlobStore, database::PluginDatabaseFindParams, Storage};
+
+/// Maximum length of a tag name
+const MAX_TAG_LENGTH: usize = 64;
+
+/// Number of times a tag move is retried when the tags have been modified by another instance
+const MAX_TAG_MOVE_ATTEMPTS: u32 = 5;
+
+/// A single movement of a tag to a file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct TagEntry {
+    pub tag: String,
+    /// Digest of the file the tag points to
+    pub digest: String,
+    #[schema(value_type = crate::rest::models::PluginFileTypeSchema)]
+    pub file_type: PluginFileType,
+    #[schema(value_type = crate::rest::models::PluginArchitectureSchema)]
+    pub architecture: PluginArchitecture,
+    /// Name of the api token which moved the tag
+    pub moved_by: String,
+    pub moved_at: NaiveDateTime,
+}
+
+/// All tags of a single plugin.
+///
+/// A tag points to one file per file type and architecture.
+/// Tags are never overwritten, every movement is appended to the history instead.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct PluginTags {
+    /// All tag movements, the oldest entry comes first
+    pub history: Vec<TagEntry>,
+}
+
+impl PluginTags {
+    /// Returns the entries each tag currently points to.
+    pub fn current(&self) -> Vec<TagEntry> {
+        let mut seen = HashSet::new();
+        let mut entries = self
+            .history
+            .iter()
+            .rev()
+            .filter(|entry| seen.insert((&entry.tag, entry.file_type, entry.architecture)))
+            .cloned()
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.tag.cmp(&b.tag));
+        entries
+    }
+
+    /// Returns the entries the given tag currently points to.
+    pub fn resolve(&self, tag: &str) -> Vec<TagEntry> {
+        self.current()
+            .into_iter()
+            .filter(|entry| entry.tag == tag)
+            .collect()
+    }
+}
+
+/// Returns true if the name can be used as a tag.
+///
+/// Tags must not be confused with versions or digests, so they have to start with a letter
+/// and must not only consist of hex digits. `latest` is reserved for the newest upload.
+pub fn is_valid_tag(tag: &str) -> bool {
+    tag.len() <= MAX_TAG_LENGTH
+        && tag != "latest"
+        && tag.starts_with(|c: char| c.is_ascii_lowercase())
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+        && !tag.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+impl Storage {
+    /// Returns all tags of the plugin.
+    pub async fn tags(&self, plugin_name: &str) -> Result<PluginTags> {
+        Ok(self.tags_versioned(plugin_name).await?.0)
+    }
+
+    /// Returns all tags of the plugin and the version of the stored tags, see [`BlobStore::put_if_version`].
+    async fn tags_versioned(&self, plugin_name: &str) -> Result<(PluginTags, Option<String>)> {
+        match self.backend.read_versioned(&tags_key(plugin_name)).await? {
+            Some((contents, version)) => Ok((serde_json::from_slice(&contents)?, Some(version))),
+            None => Ok((PluginTags::default(), None)),
+        }
+    }
+
+    /// Moves the tag of the plugin to the file with the given digest.
+    ///
+    /// Only the file type and architecture of the file are affected,
+    /// the tag keeps pointing to the same files for all other variants.
+    pub async fn move_tag(
+        &self,
+        plugin_name: &str,
+        tag: &str,
+        digest: &str,
+        moved_by: &str,
+    ) -> Result<Vec<TagEntry>> {
+        if !is_valid_tag(tag) {
+            return Err(Error::Parse(format!("invalid tag name `{}`", tag)));
+        }
+
+        let metadata = match self.metadata(digest).await {
+            Ok(metadata) if metadata.is_visible() => metadata,
+            Ok(_) | Err(Error::NotFound(_)) => {
+                return Err(Error::NotFound("digest was not found".to_owned()))
+            }
+            Err(err) => return Err(err),
+        };
+
+        // moves within this instance are serialized, moves of other instances are detected by the conditional write
+        let _guard = self.tags_lock.lock().await;
+        let moved_at = Utc::now().naive_utc();
+        let mut moved = metadata
+            .descriptors
+            .iter()
+            .filter(|descriptor| descriptor.name == plugin_name)
+            .map(|descriptor| TagEntry {
+                tag: tag.to_owned(),
+                digest: digest.to_owned(),
+                file_type: descriptor.file_type,
+                architecture: descriptor.architecture,
+                moved_by: moved_by.to_owned(),
+                moved_at,
+            })
+            .collect::<Vec<_>>();
+        let mut seen = HashSet::new();
+        moved.retain(|entry| seen.insert((entry.file_type, entry.architecture)));
+        if moved.is_empty() {
+            return Err(Error::Parse(format!(
+                "file does not contain the plugin `{}`",
+                plugin_name
+            )));
+        }
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let (mut tags, version) = self.tags_versioned(plugin_name).await?;
+
+            // moving a tag to the file it already points to does not add to the history,
+            // so repeated requests are harmless
+            let current = tags.resolve(tag);
+            let is_current = |entry: &TagEntry| {
+                current.iter().any(|current| {
+                    current.digest == entry.digest
+                        && current.file_type == entry.file_type
+                        && current.architecture == entry.architecture
+                })
+            };
+            if moved.iter().all(is_current) {
+                return Ok(current
+                    .into_iter()
+                    .filter(|entry| entry.digest == digest)
+                    .collect());
+            }
+
+            info!(
+                "moving tag {}:{} to {} by {}",
+                plugin_name, tag, digest, moved_by
+            );
+            tags.history.extend(moved.iter().cloned());
+            match self
+                .backend
+                .put_if_version(
+                    &tags_key(plugin_name),
+                    serde_json::to_vec(&tags)?.into(),
+                    version.as_deref(),
+                )
+                .await
+            {
+                Ok(()) => return Ok(moved),
+                Err(Error::AlreadyExists(err)) if attempt < MAX_TAG_MOVE_ATTEMPTS => {
+                    warn!("retrying to move tag {}:{}: {}", plugin_name, tag, err);
+                    tokio::time::sleep(Duration::from_millis(50 * attempt as u64)).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Restricts the search to the files a tag points to in case the version parameter is a tag.
+    pub(crate) async fn resolve_tag(
+        &self,
+        plugin_name: &str,
+        mut params: PluginDatabaseFindParams,
+    ) -> Result<PluginDatabaseFindParams> {
+        let Some(tag) = params.version.as_deref().filter(|tag| is_valid_tag(tag)) else {
+            return Ok(params);
+        };
+
+        let entries = self.tags(plugin_name).await?.resolve(tag);
+        if !entries.is_empty() {
+            params.version = None;
+            params.digests = Some(entries.into_iter().map(|entry| entry.digest).collect());
+        }
+        Ok(params)
+    }
+}
+
+/// Returns the storage key of the tags of a plugin
+#[inline]
+fn tags_key(plugin_name: &str) -> String {
+    format!("{}.tags", plugin_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{
+        backend::{FileSystemStore, MemoryStore},
+        database::tests::put_plugin,
+    };
+
+    use super::*;
+
+    #[test]
+    fn tag_names() {
+        assert!(is_valid_tag("stable"));
+        assert!(is_valid_tag("release-0_2"));
+        assert!(!is_valid_tag("latest"));
+        assert!(!is_valid_tag("0.2.0"));
+        assert!(!is_valid_tag("^0.2"));
+        assert!(!is_valid_tag("beef"));
+        assert!(!is_valid_tag("Stable"));
+        assert!(!is_valid_tag(""));
+    }
+
+    #[tokio::test]
+    async fn move_and_resolve() {
+        let backend = MemoryStore::new();
+        let a = put_plugin(&backend, "coredump", "0.2.0").await;
+        let b = put_plugin(&backend, "coredump", "0.2.1").await;
+        let storage = Storage::with_backend(backend).await.unwrap();
+        let find = |version: &'static str| {
+            let storage = storage.clone();
+            async move {
+                storage
+                    .plugin_variants(
+                        "coredump",
+                        PluginDatabaseFindParams {
+                            version: Some(version.to_owned()),
+                            ..Default::default()
+                        },
+                    )
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|variant| variant.digest)
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        // unknown tags do not match anything
+        assert!(find("stable").await.is_empty());
+
+        storage
+            .move_tag("coredump", "stable", &a, "ci")
+            .await
+            .unwrap();
+        storage
+            .move_tag("coredump", "nightly", &b, "ci")
+            .await
+            .unwrap();
+        assert_eq!(find("stable").await, vec![a.clone()]);
+        assert_eq!(find("nightly").await, vec![b.clone()]);
+
+        storage
+            .move_tag("coredump", "stable", &b, "release")
+            .await
+            .unwrap();
+        assert_eq!(find("stable").await, vec![b.clone()]);
+
+        // moving a tag to its current file is a no-op
+        let entries = storage
+            .move_tag("coredump", "stable", &b, "retry")
+            .await
+            .unwrap();
+        assert_eq!(entries[0].moved_by, "release");
+
+        let tags = storage.tags("coredump").await.unwrap();
+        assert_eq!(tags.history.len(), 3);
+        assert_eq!(tags.current().len(), 2);
+        assert_eq!(tags.resolve("stable")[0].moved_by, "release");
+
+        assert!(matches!(
+            storage.move_tag("coredump", "stable", "cccc", "ci").await,
+            Err(Error::NotFound(_))
+        ));
+        assert!(matches!(
+            storage.move_tag("qemu", "stable", &a, "ci").await,
+            Err(Error::Parse(_))
+        ));
+        assert!(matches!(
+            storage.move_tag("coredump", "latest", &a, "ci").await,
+            Err(Error::Parse(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn conflicting_writer() {
+        let root = tempfile::tempdir().unwrap();
+        let backend = std::sync::Arc::new(FileSystemStore::new(root.path()).unwrap());
+        let digest = put_plugin(&backend, "coredump", "0.2.0").await;
+        let storage = Storage::with_backend(backend).await.unwrap();
+
+        // another instance is writing the tags, the move gives up instead of overwriting them
+        let lock = root.path().join(".coredump.tags.lock");
+        std::fs::write(&lock, b"").unwrap();
+        assert!(matches!(
+            storage.move_tag("coredump", "stable", &digest, "ci").await,
+            Err(Error::AlreadyExists(_))
+        ));
+        assert!(storage.tags("coredump").await.unwrap().history.is_empty());
+
+        std::fs::remove_file(&lock).unwrap();
+        storage
+            .move_tag("coredump", "stable", &digest, "ci")
+            .await
+            .unwrap();
+        assert_eq!(storage.tags("coredump").await.unwrap().history.len(), 1);
+    }
+}
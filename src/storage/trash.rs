@@ -0,0 +1,162 @@
+//! Soft-delete mode which keeps deleted files for a retention period so they can be restored
+
+use std::time::Duration;
+
+use chrono::Utc;
+use log::{info, warn};
+
+use crate::error::{Error, Result, ResultExt};
+
+use super::{meta_key, plugin_key, Storage};
+
+impl Storage {
+    /// Moves deleted files into the trash instead of removing them.
+    /// Files in the trash are removed permanently by [`Storage::purge_trash`] after the retention period.
+    pub fn with_trash(mut self, retention: Duration) -> Self {
+        self.trash_retention = Some(retention);
+        self
+    }
+
+    /// Restores a file from the trash.
+    pub async fn restore(&self, digest: &str) -> Result<()> {
+        let mut metadata = match self.metadata(digest).await {
+            Ok(metadata) if metadata.deleted_at.is_some() => metadata,
+            Ok(_) | Err(Error::NotFound(_)) => {
+                return Err(Error::NotFound("digest was not found in trash".to_owned()))
+            }
+            Err(err) => return Err(err),
+        };
+
+        info!("restoring plugin {} from the trash", digest);
+        metadata.deleted_at = None;
+        self.write_metadata(&metadata).await?;
+        if metadata.is_visible() {
+            let mut database = self.database.write();
+            database.delete_by_digest(digest)?;
+            database.insert_all(&metadata)?;
+        }
+        self.record_change(digest).await;
+
+        Ok(())
+    }
+
+    /// Permanently removes all files which have been in the trash for longer than the retention period.
+    /// Returns the digests of all removed files.
+    pub async fn purge_trash(&self) -> Result<Vec<String>> {
+        let Some(retention) = self.trash_retention else {
+            return Ok(Vec::new());
+        };
+        let expired_before = Utc::now().naive_utc()
+            - chrono::Duration::from_std(retention)
+                .map_err(|err| Error::Parse(format!("invalid trash retention: {}", err)))?;
+
+        let keys = self
+            .backend
+            .list()
+            .await
+            .context("Unable to list storage contents")?;
+
+        let mut purged = Vec::new();
+        for digest in keys.iter().filter_map(|key| key.strip_suffix(".meta")) {
+            let metadata = match self.metadata(digest).await {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    warn!("unable to read metadata of {}: {}", digest, err);
+                    continue;
+                }
+            };
+            if !metadata
+                .deleted_at
+                .is_some_and(|deleted_at| deleted_at < expired_before)
+            {
+                continue;
+            }
+
+            info!("permanently removing plugin {} from the trash", digest);
+            self.backend.delete(&meta_key(digest)).await?;
+            match self.backend.delete(&plugin_key(digest)).await {
+                Ok(()) | Err(Error::NotFound(_)) => (),
+                Err(err) => return Err(err),
+            }
+            self.record_change(digest).await;
+            purged.push(digest.to_owned());
+        }
+
+        Ok(purged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{
+        backend::{BlobStore, MemoryStore},
+        database::tests::put_plugin,
+    };
+
+    use super::*;
+
+    async fn storage_with_plugin(
+        trash: Option<Duration>,
+    ) -> (Storage, std::sync::Arc<MemoryStore>, String) {
+        let backend = std::sync::Arc::new(MemoryStore::new());
+        let digest = put_plugin(&backend, "coredump", "0.2.0").await;
+
+        let mut storage = Storage::with_backend(backend.clone()).await.unwrap();
+        if let Some(retention) = trash {
+            storage = storage.with_trash(retention);
+        }
+        (storage, backend, digest)
+    }
+
+    fn variants(storage: &Storage) -> usize {
+        storage
+            .database()
+            .plugin_variants("coredump", Default::default())
+            .unwrap()
+            .len()
+    }
+
+    #[tokio::test]
+    async fn delete_removes_metadata() {
+        let (storage, backend, digest) = storage_with_plugin(None).await;
+        storage.delete(&digest).await.unwrap();
+        assert!(backend.list().await.unwrap().is_empty());
+
+        // the file does not come back after a restart
+        storage.rebuild_database().await.unwrap();
+        assert_eq!(variants(&storage), 0);
+        assert!(matches!(
+            storage.restore(&digest).await,
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn trash_and_restore() {
+        let (storage, backend, digest) = storage_with_plugin(Some(Duration::from_secs(3600))).await;
+        storage.delete(&digest).await.unwrap();
+        assert_eq!(variants(&storage), 0);
+        assert!(storage.download(&digest).await.is_err());
+        storage.rebuild_database().await.unwrap();
+        assert_eq!(variants(&storage), 0);
+
+        // files within the retention period are kept
+        assert!(storage.purge_trash().await.unwrap().is_empty());
+        storage.restore(&digest).await.unwrap();
+        assert_eq!(variants(&storage), 1);
+        assert!(storage.download(&digest).await.is_ok());
+
+        // deleting a file in the trash removes it permanently
+        storage.delete(&digest).await.unwrap();
+        storage.delete(&digest).await.unwrap();
+        assert!(backend.list().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn purge_expired() {
+        let (storage, backend, digest) = storage_with_plugin(Some(Duration::ZERO)).await;
+        storage.delete(&digest).await.unwrap();
+        assert_eq!(storage.purge_trash().await.unwrap(), vec![digest]);
+        assert!(backend.list().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,365 @@
+//! Pull-through mode which mirrors plugins from an upstream registry on demand
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use futures_util::StreamExt;
+use log::{info, warn};
+use parking_lot::Mutex;
+
+use crate::{
+    client::{to_http_err, RegistryClient},
+    error::{Error, Result},
+    pki::SignatureVerifier,
+};
+
+use super::{
+    database::{PluginDatabaseFindParams, PluginVariant},
+    plugin_key,
+    spool::PluginContents,
+    Storage, UploadResponse,
+};
+
+/// Upstream registry from which missing plugins are fetched
+#[derive(Clone)]
+pub struct Upstream {
+    registry: String,
+    client: RegistryClient,
+    key_id: String,
+    verifier: SignatureVerifier,
+    // serializes mirroring of the same digest so concurrent requests do not store a file twice
+    locks: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+impl Upstream {
+    /// Creates a new upstream.
+    /// All mirrored files have to be signed by the given verifier and are recorded with the given key id.
+    pub fn new(registry: &str, key_id: &str, verifier: SignatureVerifier) -> Result<Self> {
+        Ok(Self {
+            registry: registry.to_owned(),
+            client: RegistryClient::new(Some(registry))?,
+            key_id: key_id.to_owned(),
+            verifier,
+            locks: Default::default(),
+        })
+    }
+
+    #[inline]
+    pub fn registry(&self) -> &str {
+        &self.registry
+    }
+
+    #[inline]
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    #[inline]
+    pub fn verifier(&self) -> &SignatureVerifier {
+        &self.verifier
+    }
+
+    /// Checks if the upstream registry is reachable.
+    pub async fn health(&self) -> Result<()> {
+        self.client.health().await
+    }
+
+    /// Returns the lock which guards mirroring of the given digest.
+    fn lock(&self, digest: &str) -> Arc<tokio::sync::Mutex<()>> {
+        self.locks
+            .lock()
+            .entry(digest.to_owned())
+            .or_default()
+            .clone()
+    }
+
+    /// Removes the lock of the given digest once no other request is waiting for it anymore.
+    fn release(&self, digest: &str, lock: Arc<tokio::sync::Mutex<()>>) {
+        let mut locks = self.locks.lock();
+        // one reference is held by the map and one by the caller
+        if Arc::strong_count(&lock) <= 2 {
+            locks.remove(digest);
+        }
+    }
+}
+
+impl Storage {
+    /// Enables pull-through mode for the given upstream registry.
+    pub fn with_upstream(mut self, upstream: Upstream) -> Self {
+        self.upstream = Some(upstream);
+        self
+    }
+
+    /// Retrieves a list of variants for a specific plugin from the database.
+    ///
+    /// A tag in the version parameter is resolved to the files it points to.
+    /// In pull-through mode the variants are mirrored from the upstream registry
+    /// in case no matching variants are stored locally.
+    pub async fn plugin_variants(
+        &self,
+        plugin_name: &str,
+        params: PluginDatabaseFindParams,
+    ) -> Result<Vec<PluginVariant>> {
+        let params = self.resolve_tag(plugin_name, params).await?;
+        let variants = self
+            .database()
+            .plugin_variants(plugin_name, params.clone())?;
+        let Some(upstream) = &self.upstream else {
+            return Ok(variants);
+        };
+        // local tags are never resolved upstream
+        if !variants.is_empty() || params.digests.is_some() {
+            return Ok(variants);
+        }
+
+        // an unavailable upstream must not break queries that could be answered locally
+        let upstream_variants = match upstream.client.find_variants(plugin_name, &params).await {
+            Ok(upstream_variants) => upstream_variants,
+            Err(err) => {
+                warn!(
+                    "unable to find variants of {} on {}: {}",
+                    plugin_name,
+                    upstream.registry(),
+                    err
+                );
+                return Ok(variants);
+            }
+        };
+        if upstream_variants.is_empty() {
+            return Ok(variants);
+        }
+
+        for variant in upstream_variants.iter() {
+            if let Err(err) = self.mirror(&variant.digest).await {
+                warn!(
+                    "unable to mirror plugin {} from {}: {}",
+                    variant.digest,
+                    upstream.registry(),
+                    err
+                );
+            }
+        }
+
+        self.database().plugin_variants(plugin_name, params)
+    }
+
+    /// Fetches the file with the given digest from the upstream registry,
+    /// verifies it and adds it to the storage.
+    pub(crate) async fn mirror(&self, digest: &str) -> Result<UploadResponse> {
+        let upstream = self
+            .upstream
+            .as_ref()
+            .ok_or_else(|| Error::NotFound("digest was not found".to_owned()))?;
+        let lock = upstream.lock(digest);
+        let result = {
+            let _guard = lock.lock().await;
+            self.mirror_locked(upstream, digest).await
+        };
+        upstream.release(digest, lock);
+        result
+    }
+
+    async fn mirror_locked(&self, upstream: &Upstream, digest: &str) -> Result<UploadResponse> {
+        if self.backend.stat(&plugin_key(digest)).await?.is_some() {
+            return Ok(UploadResponse::AlreadyExists);
+        }
+
+        info!("mirroring plugin {} from {}", digest, upstream.registry());
+        let metadata = upstream.client.metadata(digest).await?;
+
+        // stream the file into a temporary file like regular uploads
+        let mut file = self.spool()?;
+        let mut stream = upstream.client.download(digest).await?.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            file.write(&chunk.map_err(to_http_err)?).await?;
+        }
+        file.finish().await?;
+
+        // ensure the upstream served the requested file
+        let actual_digest = file.digest();
+        if actual_digest != digest || metadata.digest != digest {
+            return Err(Error::Signature(format!(
+                "upstream served file with digest {} for {}",
+                actual_digest, digest
+            )));
+        }
+
+        let valid = if upstream.verifier.scheme().supports_digest() {
+            upstream
+                .verifier
+                .is_valid_digest(file.hasher().clone(), &metadata.signature)
+        } else {
+            upstream
+                .verifier
+                .is_valid(&file.map()?[..], &metadata.signature)
+        };
+        if let Err(err) = valid {
+            warn!("invalid upstream signature for plugin {}: {}", digest, err);
+            return Err(Error::Signature("file signature is invalid".to_owned()));
+        }
+
+        self.store(
+            PluginContents::Spooled(&file),
+            &metadata.signature,
+            Some(upstream.key_id().to_owned()),
+            metadata.created_at,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pki::tests::key_pair;
+    use crate::storage::{
+        backend::{BlobStore, MemoryStore},
+        database::tests::{plugin_binary, put_signed_plugin, serve},
+    };
+
+    use super::*;
+
+    /// Serves a registry with a single (non-binary) file signed by the given key
+    async fn serve_upstream(generator: &mut crate::pki::SignatureGenerator) -> (String, String) {
+        let backend = MemoryStore::new();
+        let digest = put_signed_plugin(&backend, "coredump", "0.2.0", generator).await;
+        let storage = Storage::with_backend(backend).await.unwrap();
+        (serve(storage).await, digest)
+    }
+
+    #[tokio::test]
+    async fn mirror_binary() {
+        let (mut alice, alice_pem) = key_pair(1);
+        let binary = plugin_binary();
+        let upstream = Storage::with_backend(MemoryStore::new()).await.unwrap();
+        upstream
+            .upload(&binary, &alice.sign(&binary).unwrap())
+            .await
+            .unwrap();
+        let digest = sha256::digest(&binary[..]);
+        let expected = upstream.metadata(&digest).await.unwrap();
+        let registry = serve(upstream).await;
+
+        let backend = std::sync::Arc::new(MemoryStore::new());
+        let storage = Storage::with_backend(backend.clone())
+            .await
+            .unwrap()
+            .with_upstream(
+                Upstream::new(
+                    &registry,
+                    "upstream",
+                    SignatureVerifier::with_str(&alice_pem).unwrap(),
+                )
+                .unwrap(),
+            );
+
+        // the missing file is fetched from the upstream
+        let (info, stream) = storage.download(&digest).await.unwrap();
+        let downloaded = stream
+            .map(|chunk| chunk.unwrap())
+            .collect::<Vec<_>>()
+            .await
+            .concat();
+        assert_eq!(info.size, binary.len() as u64);
+        assert_eq!(downloaded, binary);
+
+        // and stored locally with the metadata of the upstream
+        let metadata = storage.metadata(&digest).await.unwrap();
+        assert_eq!(metadata.signature, expected.signature);
+        assert_eq!(metadata.created_at, expected.created_at);
+        assert_eq!(metadata.descriptors, expected.descriptors);
+        assert_eq!(metadata.descriptors[0].name, "test");
+        assert_eq!(metadata.key_id.as_deref(), Some("upstream"));
+        assert_eq!(
+            &backend.read(&plugin_key(&digest)).await.unwrap()[..],
+            &binary[..]
+        );
+        let variants = storage
+            .database()
+            .plugin_variants("test", Default::default())
+            .unwrap();
+        assert_eq!(variants.len(), 1);
+        assert_eq!(variants[0].digest, digest);
+
+        // further requests are served locally
+        assert!(matches!(
+            storage.mirror(&digest).await.unwrap(),
+            UploadResponse::AlreadyExists
+        ));
+    }
+
+    #[tokio::test]
+    async fn pull_through() {
+        let (mut alice, alice_pem) = key_pair(1);
+        let (_, bob_pem) = key_pair(2);
+        let (registry, digest) = serve_upstream(&mut alice).await;
+
+        // files signed by an untrusted upstream key are rejected
+        let storage = Storage::with_backend(MemoryStore::new())
+            .await
+            .unwrap()
+            .with_upstream(
+                Upstream::new(
+                    &registry,
+                    "upstream",
+                    SignatureVerifier::with_str(&bob_pem).unwrap(),
+                )
+                .unwrap(),
+            );
+        assert!(matches!(
+            storage.mirror(&digest).await,
+            Err(Error::Signature(_))
+        ));
+
+        // valid signatures are accepted but the file still has to pass the plugin analyzer
+        let storage = storage.with_upstream(
+            Upstream::new(
+                &registry,
+                "upstream",
+                SignatureVerifier::with_str(&alice_pem).unwrap(),
+            )
+            .unwrap(),
+        );
+        assert!(matches!(
+            storage.mirror(&digest).await,
+            Err(Error::Memflow(_))
+        ));
+
+        // failed mirrors are not served
+        assert!(storage
+            .plugin_variants("coredump", Default::default())
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(storage.download(&digest).await.is_err());
+
+        // unknown digests are not found upstream either
+        assert!(matches!(
+            storage.mirror("abcdef").await,
+            Err(Error::NotFound(_))
+        ));
+        assert!(storage.upstream.as_ref().unwrap().locks.lock().is_empty());
+
+        // queries still succeed while the upstream is unavailable
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let unavailable = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let storage = storage.with_upstream(Upstream {
+            client: RegistryClient::builder()
+                .with_registry(&unavailable)
+                .with_retry_policy(crate::client::RetryPolicy::none())
+                .build()
+                .unwrap(),
+            ..Upstream::new(
+                &unavailable,
+                "upstream",
+                SignatureVerifier::with_str(&alice_pem).unwrap(),
+            )
+            .unwrap()
+        });
+        assert!(storage
+            .plugin_variants("coredump", Default::default())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,217 @@
+use chrono::Utc;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::error::{Error, Result, ResultExt};
+
+use super::{plugin_key, PluginMetadata, Storage};
+
+/// Storage key under which the fingerprint of the last verified keyring is stored
+const KEYRING_FINGERPRINT_KEY: &str = "keyring.fingerprint";
+
+/// Changes made by a verification pass over all stored files
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
+pub struct VerifyReport {
+    /// Number of files that have been checked
+    pub checked: usize,
+    /// Digests of files that could not be verified anymore and have been quarantined
+    pub quarantined: Vec<String>,
+    /// Digests of previously quarantined files that have been verified again and were restored
+    pub restored: Vec<String>,
+    /// Digests of files which are now attributed to a different key id
+    pub rekeyed: Vec<String>,
+    /// Metadata files that could not be checked
+    pub failed: Vec<String>,
+}
+
+impl Storage {
+    /// Re-verifies the signatures of all stored files against the current keyring.
+    ///
+    /// Files that do not verify anymore are quarantined and hidden from the database.
+    /// Quarantined files that verify again are restored.
+    pub async fn verify_all(&self) -> Result<VerifyReport> {
+        let keyring = self
+            .keyring
+            .as_ref()
+            .ok_or_else(|| Error::Signature("no trusted keys are configured".to_owned()))?;
+
+        let keys = self
+            .backend
+            .list()
+            .await
+            .context("Unable to list storage contents")?;
+
+        let mut report = VerifyReport::default();
+        for key in keys.iter().filter(|key| key.ends_with(".meta")) {
+            let (mut metadata, bytes) = match self.read_metadata_and_plugin(key).await {
+                Ok(result) => result,
+                Err(err) => {
+                    warn!("unable to verify {}: {}", key, err);
+                    report.failed.push(key.to_owned());
+                    continue;
+                }
+            };
+            report.checked += 1;
+
+            let digest = metadata.digest.clone();
+            match keyring.verify(&bytes, &metadata.signature) {
+                Ok(key_id) => {
+                    let key_id = Some(key_id.to_owned());
+                    if metadata.quarantined_at.is_some() {
+                        info!("restoring plugin {} signed by key `{:?}`", digest, key_id);
+                        metadata.quarantined_at = None;
+                        report.restored.push(digest.clone());
+                    } else if metadata.key_id != key_id {
+                        info!(
+                            "plugin {} is now attributed to key `{:?}` (was `{:?}`)",
+                            digest, key_id, metadata.key_id
+                        );
+                        report.rekeyed.push(digest.clone());
+                    } else {
+                        continue;
+                    }
+
+                    metadata.key_id = key_id;
+                    self.write_metadata(&metadata).await?;
+
+                    {
+                        let mut database = self.database.write();
+                        database.delete_by_digest(&digest)?;
+                        if metadata.is_visible() {
+                            database.insert_all(&metadata)?;
+                        }
+                    }
+                    self.record_change(&digest).await;
+                }
+                Err(_) if metadata.quarantined_at.is_some() => {}
+                Err(_) => {
+                    warn!(
+                        "signature of plugin {} does not match any trusted key, quarantining it",
+                        digest
+                    );
+                    metadata.quarantined_at = Some(Utc::now().naive_utc());
+                    self.write_metadata(&metadata).await?;
+                    self.database.write().delete_by_digest(&digest)?;
+                    self.record_change(&digest).await;
+                    report.quarantined.push(digest);
+                }
+            }
+        }
+
+        info!(
+            "verified {} plugins: {} quarantined, {} restored, {} rekeyed, {} failed",
+            report.checked,
+            report.quarantined.len(),
+            report.restored.len(),
+            report.rekeyed.len(),
+            report.failed.len()
+        );
+
+        Ok(report)
+    }
+
+    /// Runs [`Storage::verify_all`] in case the keyring changed since the last verification.
+    pub async fn verify_on_keyring_change(&self) -> Result<Option<VerifyReport>> {
+        let Some(keyring) = &self.keyring else {
+            return Ok(None);
+        };
+
+        let fingerprint = keyring.fingerprint();
+        if self.backend.stat(KEYRING_FINGERPRINT_KEY).await?.is_some() {
+            let previous = self.backend.read(KEYRING_FINGERPRINT_KEY).await?;
+            if previous == fingerprint.as_bytes() {
+                return Ok(None);
+            }
+        }
+
+        info!("trusted keys changed, re-verifying all stored plugins");
+        let report = self.verify_all().await?;
+        self.backend
+            .put(KEYRING_FINGERPRINT_KEY, fingerprint.into())
+            .await?;
+        Ok(Some(report))
+    }
+
+    async fn read_metadata_and_plugin(&self, key: &str) -> Result<(PluginMetadata, Vec<u8>)> {
+        let metadata: PluginMetadata = serde_json::from_slice(&self.backend.read(key).await?)?;
+        let bytes = self.backend.read(&plugin_key(&metadata.digest)).await?;
+        Ok((metadata, bytes.to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pki::{tests::key_pair, Keyring, SignatureVerifier};
+    use crate::storage::{backend::MemoryStore, database::tests::put_signed_plugin};
+
+    use super::*;
+
+    fn keyring(keys: &[(&str, &str)]) -> Keyring {
+        let mut keyring = Keyring::new();
+        for (id, pem) in keys {
+            keyring
+                .insert(id, SignatureVerifier::with_str(pem).unwrap())
+                .unwrap();
+        }
+        keyring
+    }
+
+    #[tokio::test]
+    async fn quarantine_and_restore() {
+        let (mut alice, alice_pem) = key_pair(1);
+        let (_, bob_pem) = key_pair(2);
+
+        // store a plugin signed by alice
+        let backend = MemoryStore::new();
+        let digest = put_signed_plugin(&backend, "coredump", "0.2.0", &mut alice).await;
+        let storage = Storage::with_backend(backend).await.unwrap();
+        let variants = |storage: &Storage| {
+            storage
+                .database()
+                .plugin_variants("coredump", Default::default())
+                .unwrap()
+                .len()
+        };
+
+        // the key id is recorded on the first verification
+        let report = storage
+            .clone()
+            .with_keyring(keyring(&[("alice", &alice_pem)]))
+            .verify_on_keyring_change()
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(report.rekeyed, vec![digest.clone()]);
+        assert_eq!(
+            storage.metadata(&digest).await.unwrap().key_id.as_deref(),
+            Some("alice")
+        );
+
+        // alice is no longer trusted
+        let bob_only = storage.clone().with_keyring(keyring(&[("bob", &bob_pem)]));
+        let report = bob_only.verify_on_keyring_change().await.unwrap().unwrap();
+        assert_eq!(report.quarantined, vec![digest.clone()]);
+        assert_eq!(variants(&storage), 0);
+        assert!(matches!(
+            storage.download(&digest).await,
+            Err(Error::NotFound(_))
+        ));
+        assert!(bob_only.verify_on_keyring_change().await.unwrap().is_none());
+
+        // quarantined files stay hidden after a rebuild
+        storage.rebuild_database().await.unwrap();
+        assert_eq!(variants(&storage), 0);
+
+        // alice is trusted again
+        let report = storage
+            .clone()
+            .with_keyring(keyring(&[("bob", &bob_pem), ("alice", &alice_pem)]))
+            .verify_all()
+            .await
+            .unwrap();
+        assert_eq!(report.restored, vec![digest.clone()]);
+        assert_eq!(variants(&storage), 1);
+        assert!(storage.download(&digest).await.is_ok());
+    }
+}
@@ -0,0 +1,32 @@
+/*
+ * Minimal memflow connector descriptor used by the registry tests.
+ * Build with: cc -shared -fPIC -nostdlib -s -Wl,-z,max-page-size=0x1000 -o connector.x86_64.so connector.c
+ */
+struct descriptor {
+    int plugin_version;
+    unsigned int accept_input;
+    const void *input_layout;
+    const void *output_layout;
+    const char *name;
+    unsigned int name_length;
+    unsigned int pad0;
+    const char *version;
+    unsigned int version_length;
+    unsigned int pad1;
+    const char *description;
+    unsigned int description_length;
+    unsigned int pad2;
+    const void *help_callback;
+    const void *target_list_callback;
+    const void *create;
+};
+
+__attribute__((visibility("default"))) const struct descriptor MEMFLOW_CONNECTOR_TEST = {
+    .plugin_version = 1,
+    .name = "test",
+    .name_length = 4,
+    .version = "0.1.0",
+    .version_length = 5,
+    .description = "connector used by the registry tests",
+    .description_length = 36,
+};